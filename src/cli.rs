@@ -0,0 +1,351 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use glm::Vec3;
+use serde::Deserialize;
+use tinyrenderer::{
+    draw::{
+        msaa::SampleCount,
+        ssaa::{self, DownsampleFilter},
+        texture::{Sampler, WrapMode},
+        CullMode,
+    },
+    parse_name, RenderError,
+};
+
+pub const USAGE: &str = "\
+usage: tinyrenderer [options]
+
+options:
+  --scene <path>         render a TOML/JSON scene file, other options are ignored
+  --model <path>         OBJ model           [default: obj/diablo3/diablo3_pose.obj]
+  --diffuse <path>       diffuse texture     [default: obj/african_head/african_head_diffuse.tga]
+  --normal <path>        normal map          [default: obj/african_head/african_head_nm.tga]
+  --normal-space <name>  space of the normal map: object | tangent   [default: object]
+  --specular <path>      specular map        [default: obj/african_head/african_head_spec.tga]
+  --shader <name>        gouraud | phong | pbr | deferred | shadow (alias: depth)   [default: phong]
+  --metallic <f>         metallic factor in [0,1] (pbr)   [default: 0]
+  --roughness <f>        roughness factor in [0,1] (pbr)   [default: 0.5]
+  --metallic-roughness <path>  glTF metallic-roughness map, multiplied by the factors (pbr)
+  --emissive <path>      emissive map (pbr)
+  --msaa <n>             samples per pixel: 1 | 2 | 4 | 8   [default: 1]
+  --ssaa <n>             render at n times the resolution and downsample, at most 16   [default: 1]
+  --ssaa-filter <name>   box | tent | lanczos | mitchell   [default: box]
+  --texture-filter <name>  nearest | bilinear   [default: bilinear]
+  --texture-wrap <mode>  repeat | clamp | mirror   [default: repeat]
+  --mipmap <mode>        none | nearest | linear   [default: linear]
+  --anisotropy <n>       max samples for anisotropic filtering, 1 disables it   [default: 1]
+  --ssao                 darken the ambient term with screen-space ambient occlusion (phong, deferred)
+  --ao-map <path>        baked ambient occlusion map multiplied into the ambient term (phong, pbr)
+  --bake-ao <path>       bake an ambient occlusion map of --width x --height for --model and exit
+  --cull <mode>          none | front | back (counter-clockwise faces are front)   [default: back]
+  --eye <x,y,z>          camera position     [default: 1,1,3]
+  --center <x,y,z>       camera target       [default: 0,0,0]
+  --up <x,y,z>           camera up vector    [default: 0,1,0]
+  --light <x,y,z>        light direction     [default: 1,1,0]
+  --width <n>            image width         [default: 800]
+  --height <n>           image height        [default: 800]
+  --output <path>        color image output  [default: a.png]
+  --depth-output <path>  depth image output  [default: b.png]
+  -h, --help             print this help
+
+textures from the model's MTL materials take precedence over --diffuse/--normal/--specular
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShaderKind {
+    Gouraud,
+    Phong,
+    Pbr,
+    Deferred,
+    #[serde(alias = "depth")]
+    Shadow,
+}
+
+impl FromStr for ShaderKind {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalSpace {
+    Object,
+    Tangent,
+}
+
+impl FromStr for NormalSpace {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub scene: Option<String>,
+    pub model: String,
+    pub diffuse: String,
+    pub normal: String,
+    pub normal_space: NormalSpace,
+    pub specular: String,
+    pub shader: ShaderKind,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub metallic_roughness: Option<String>,
+    pub emissive: Option<String>,
+    pub cull: CullMode,
+    pub samples: SampleCount,
+    pub ssaa: u32,
+    pub ssaa_filter: DownsampleFilter,
+    pub sampler: Sampler,
+    pub ssao: bool,
+    pub ao_map: Option<String>,
+    pub bake_ao: Option<String>,
+    pub eye: Vec3,
+    pub center: Vec3,
+    pub up: Vec3,
+    pub light_dir: Vec3,
+    pub width: u32,
+    pub height: u32,
+    pub output: String,
+    pub depth_output: String,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            scene: None,
+            model: "obj/diablo3/diablo3_pose.obj".into(),
+            diffuse: "obj/african_head/african_head_diffuse.tga".into(),
+            normal: "obj/african_head/african_head_nm.tga".into(),
+            normal_space: NormalSpace::Object,
+            specular: "obj/african_head/african_head_spec.tga".into(),
+            shader: ShaderKind::Phong,
+            metallic: 0.,
+            roughness: 0.5,
+            metallic_roughness: None,
+            emissive: None,
+            cull: CullMode::Back,
+            samples: SampleCount::X1,
+            ssaa: 1,
+            ssaa_filter: DownsampleFilter::Box,
+            sampler: Sampler::default(),
+            ssao: false,
+            ao_map: None,
+            bake_ao: None,
+            eye: glm::vec3(1., 1., 3.),
+            center: glm::vec3(0., 0., 0.),
+            up: glm::vec3(0., 1., 0.),
+            light_dir: glm::vec3(1., 1., 0.),
+            width: 800,
+            height: 800,
+            output: "a.png".into(),
+            depth_output: "b.png".into(),
+        }
+    }
+}
+
+impl Args {
+    /// 解析命令行参数，不包含程序名
+    ///
+    /// 返回None表示用户请求了帮助信息
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Option<Args>> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            if flag == "-h" || flag == "--help" {
+                return Ok(None);
+            }
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("missing value for `{}`", flag))
+            };
+            match flag.as_str() {
+                "--scene" => parsed.scene = Some(value()?),
+                "--model" => parsed.model = value()?,
+                "--diffuse" => parsed.diffuse = value()?,
+                "--normal" => parsed.normal = value()?,
+                "--normal-space" => parsed.normal_space = parse(&flag, &value()?)?,
+                "--specular" => parsed.specular = value()?,
+                "--shader" => parsed.shader = parse(&flag, &value()?)?,
+                "--metallic" => parsed.metallic = parse_unit(&flag, &value()?)?,
+                "--roughness" => parsed.roughness = parse_unit(&flag, &value()?)?,
+                "--metallic-roughness" => parsed.metallic_roughness = Some(value()?),
+                "--emissive" => parsed.emissive = Some(value()?),
+                "--cull" => parsed.cull = parse(&flag, &value()?)?,
+                "--msaa" => parsed.samples = parse(&flag, &value()?)?,
+                "--ssaa" => parsed.ssaa = parse_ssaa(&value()?)?,
+                "--ssaa-filter" => parsed.ssaa_filter = parse(&flag, &value()?)?,
+                "--texture-filter" => parsed.sampler.filter = parse(&flag, &value()?)?,
+                "--texture-wrap" => {
+                    let wrap = parse::<WrapMode>(&flag, &value()?)?;
+                    parsed.sampler.wrap_u = wrap;
+                    parsed.sampler.wrap_v = wrap;
+                }
+                "--mipmap" => parsed.sampler.mipmap = parse(&flag, &value()?)?,
+                "--ssao" => parsed.ssao = true,
+                "--ao-map" => parsed.ao_map = Some(value()?),
+                "--bake-ao" => parsed.bake_ao = Some(value()?),
+                "--anisotropy" => parsed.sampler.max_anisotropy = parse_size(&flag, &value()?)?,
+                "--eye" => parsed.eye = parse_vec3(&flag, &value()?)?,
+                "--center" => parsed.center = parse_vec3(&flag, &value()?)?,
+                "--up" => parsed.up = parse_vec3(&flag, &value()?)?,
+                "--light" => parsed.light_dir = parse_vec3(&flag, &value()?)?,
+                "--width" => parsed.width = parse_size(&flag, &value()?)?,
+                "--height" => parsed.height = parse_size(&flag, &value()?)?,
+                "--output" => parsed.output = value()?,
+                "--depth-output" => parsed.depth_output = value()?,
+                _ => bail!("unknown option `{}`, see --help", flag),
+            }
+        }
+        if glm::length(parsed.eye - parsed.center) <= f32::EPSILON {
+            bail!("--eye and --center must not be the same point");
+        }
+        if glm::length(parsed.light_dir) <= f32::EPSILON {
+            bail!("--light must not be a zero vector");
+        }
+        if parsed.shader == ShaderKind::Deferred && parsed.samples != SampleCount::X1 {
+            bail!("--shader deferred does not support --msaa");
+        }
+        Ok(Some(parsed))
+    }
+}
+
+// 枚举的名字和场景文件里的一致
+fn parse<T: FromStr<Err = RenderError>>(flag: &str, s: &str) -> Result<T> {
+    s.parse()
+        .with_context(|| format!("invalid value `{}` for `{}`", s, flag))
+}
+
+fn parse_vec3(flag: &str, s: &str) -> Result<Vec3> {
+    let v = s
+        .split(',')
+        .map(|c| c.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid value `{}` for `{}`, expected x,y,z", s, flag))?;
+    match v[..] {
+        [x, y, z] => Ok(glm::vec3(x, y, z)),
+        _ => bail!("invalid value `{}` for `{}`, expected x,y,z", s, flag),
+    }
+}
+
+fn parse_size(flag: &str, s: &str) -> Result<u32> {
+    match s.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!(
+            "invalid value `{}` for `{}`, expected a positive integer",
+            s,
+            flag
+        ),
+    }
+}
+
+fn parse_ssaa(s: &str) -> Result<u32> {
+    match s.parse::<u32>() {
+        Ok(n) if (1..=ssaa::MAX_FACTOR).contains(&n) => Ok(n),
+        _ => bail!(
+            "invalid value `{}` for `--ssaa`, expected an integer between 1 and {}",
+            s,
+            ssaa::MAX_FACTOR
+        ),
+    }
+}
+
+fn parse_unit(flag: &str, s: &str) -> Result<f32> {
+    match s.parse::<f32>() {
+        Ok(v) if (0. ..=1.).contains(&v) => Ok(v),
+        _ => bail!(
+            "invalid value `{}` for `{}`, expected a number in [0,1]",
+            s,
+            flag
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tinyrenderer::draw::texture::{FilterMode, MipmapMode};
+
+    fn parse_args(args: &str) -> Result<Option<Args>> {
+        Args::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn no_arguments_gives_defaults() {
+        let args = parse_args("").unwrap().unwrap();
+        assert_eq!(args.shader, ShaderKind::Phong);
+        assert_eq!(args.cull, CullMode::Back);
+        assert_eq!(args.samples, SampleCount::X1);
+        assert_eq!(args.sampler, Sampler::default());
+        assert_eq!((args.width, args.height), (800, 800));
+    }
+
+    #[test]
+    fn help_returns_none() {
+        assert!(parse_args("--width 10 --help").unwrap().is_none());
+        assert!(parse_args("-h").unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_enum_names() {
+        let args = parse_args(
+            "--shader depth --normal-space tangent --cull none --msaa 4 --ssaa-filter lanczos \
+             --texture-filter nearest --texture-wrap mirror --mipmap none",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(args.shader, ShaderKind::Shadow);
+        assert_eq!(args.normal_space, NormalSpace::Tangent);
+        assert_eq!(args.cull, CullMode::None);
+        assert_eq!(args.samples, SampleCount::X4);
+        assert_eq!(args.ssaa_filter, DownsampleFilter::Lanczos);
+        assert_eq!(args.sampler.filter, FilterMode::Nearest);
+        assert_eq!(
+            (args.sampler.wrap_u, args.sampler.wrap_v),
+            (WrapMode::Mirror, WrapMode::Mirror)
+        );
+        assert_eq!(args.sampler.mipmap, MipmapMode::None);
+    }
+
+    #[test]
+    fn parses_numbers_and_vectors() {
+        let args = parse_args("--eye 0,0,5 --light 0,1,0 --width 64 --ssaa 2 --roughness 1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(args.eye, glm::vec3(0., 0., 5.));
+        assert_eq!(args.light_dir, glm::vec3(0., 1., 0.));
+        assert_eq!((args.width, args.ssaa, args.roughness), (64, 2, 1.));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        for args in [
+            "--cull sideways",
+            "--msaa 3",
+            "--ssaa 17",
+            "--width 0",
+            "--metallic 2",
+            "--eye 1,2",
+            "--light 0,0,0",
+            "--eye 0,0,0",
+            "--shader deferred --msaa 4",
+            "--bogus",
+            "--model",
+        ] {
+            assert!(parse_args(args).is_err(), "{}", args);
+        }
+        let e = parse_args("--cull sideways").unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("`none`, `front`, `back`"),
+            "{:#}",
+            e
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! 烘焙环境光遮蔽贴图
+//!
+//! 在包围球上随机取几百个方向，每个方向用阴影pass从外面渲染一次深度，
+//! 统计模型表面每个纹素被照到的比例，结果按模型的uv存成贴图，
+//! 离线计算一次以后可以直接交给 PhongShader::with_occlusion_map 使用
+//!
+//! 和SSAO相比不受视角影响，也能看到屏幕外的遮挡物，但只适用于不变形的模型
+
+use std::{f32::consts::PI, path::Path, thread};
+
+use glm::Vec3;
+use image::{imageops::flip_vertical_in_place, ImageBuffer, Luma};
+use obj::TexturedVertex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    bounding_sphere,
+    error::{RenderError, Result},
+    model::check_mesh,
+};
+
+use super::{edge, light::Light, shadow::ShadowMap, target::Plane};
+
+type Mesh = obj::Obj<TexturedVertex, u32>;
+
+/// 烘焙参数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AoBake {
+    /// 采样方向的个数，每个方向渲染一次阴影贴图
+    pub directions: u32,
+    /// 阴影贴图的边长(像素)
+    pub resolution: u32,
+    /// 深度偏移，防止表面自遮挡，含义同 ShadowMap::bias
+    pub bias: f32,
+    /// uv岛外向外扩展的纹素数，避免双线性过滤在接缝处采样到空白
+    pub padding: u32,
+    /// 随机数种子，同样的种子得到同样的结果
+    pub seed: u64,
+}
+
+impl Default for AoBake {
+    fn default() -> Self {
+        Self {
+            directions: 256,
+            resolution: 1024,
+            bias: 0.005,
+            padding: 4,
+            seed: 0,
+        }
+    }
+}
+
+// uv空间中被模型覆盖的纹素
+#[derive(Debug, Clone, Copy)]
+struct Texel {
+    index: usize,
+    position: Vec3,
+    normal: Vec3,
+}
+
+impl AoBake {
+    /// 烘焙width*height的遮蔽系数，(x,y)对应uv ((x+0.5)/width, (y+0.5)/height)
+    ///
+    /// meshes既是遮挡物也是烘焙对象，它们共用同一套uv，没有被覆盖的纹素为1
+    pub fn bake(&self, meshes: &[&Mesh], width: u32, height: u32) -> Result<Plane<f32>> {
+        for mesh in meshes {
+            check_mesh(mesh)?;
+        }
+        let mut occlusion = Plane::new(width, height, 1.);
+        let positions = meshes
+            .iter()
+            .flat_map(|mesh| mesh.vertices.iter())
+            .map(|v| *Vec3::from_array(&v.position));
+        let (center, radius) = match bounding_sphere(positions) {
+            Some(sphere) => sphere,
+            None => return Ok(occlusion),
+        };
+        let texels = rasterize_uv(meshes, width, height);
+        let directions = self.sample_directions();
+
+        // 每个线程处理一部分方向，各自累加后再合并
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk = directions.len().div_ceil(threads).max(1);
+        let partials = thread::scope(|s| {
+            let handles: Vec<_> = directions
+                .chunks(chunk)
+                .map(|dirs| {
+                    let texels = &texels;
+                    s.spawn(move || self.accumulate(meshes, texels, dirs, center, radius))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("ao bake thread panicked"))
+                .collect::<Vec<_>>()
+        });
+        let mut visible = vec![0.; texels.len()];
+        let mut weight = vec![0.; texels.len()];
+        for partial in partials {
+            let (v, w) = partial?;
+            visible.iter_mut().zip(v).for_each(|(sum, v)| *sum += v);
+            weight.iter_mut().zip(w).for_each(|(sum, w)| *sum += w);
+        }
+
+        let mut covered = Plane::new(width, height, false);
+        for (i, texel) in texels.iter().enumerate() {
+            let (x, y) = (texel.index as u32 % width, texel.index as u32 / width);
+            if weight[i] > 0. {
+                occlusion.set(x, y, visible[i] / weight[i]);
+            }
+            covered.set(x, y, true);
+        }
+        dilate(&mut occlusion, &mut covered, self.padding);
+        Ok(occlusion)
+    }
+
+    // 单位球面上均匀分布的方向
+    fn sample_directions(&self) -> Vec<Vec3> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        (0..self.directions.max(1))
+            .map(|_| {
+                let z: f32 = rng.gen_range(-1.0..1.0);
+                let phi = rng.gen_range(0.0..2. * PI);
+                let r = (1. - z * z).sqrt();
+                glm::vec3(r * phi.cos(), r * phi.sin(), z)
+            })
+            .collect()
+    }
+
+    // 从dirs中的每个方向看过去，按余弦加权累加每个纹素的可见度和权重
+    fn accumulate(
+        &self,
+        meshes: &[&Mesh],
+        texels: &[Texel],
+        dirs: &[Vec3],
+        center: Vec3,
+        radius: f32,
+    ) -> Result<(Vec<f32>, Vec<f32>)> {
+        let size = self.resolution.max(1);
+        // 沿法线偏移一个阴影贴图纹素的距离再查找，掠射方向上深度偏移不够用
+        let normal_offset = 2. * radius / size as f32;
+        let mut visible = vec![0.; texels.len()];
+        let mut weight = vec![0.; texels.len()];
+        for &dir in dirs {
+            // 平行光的阴影贴图正好包住包围球
+            let light = Light::directional(dir);
+            let mut shadow_map =
+                ShadowMap::for_light(&light, center, radius, size, size, self.bias);
+            for mesh in meshes {
+                shadow_map.render(mesh)?;
+            }
+            for (i, texel) in texels.iter().enumerate() {
+                // 背对这个方向的表面照不到
+                let cos = glm::dot(texel.normal, dir);
+                if cos <= 0. {
+                    continue;
+                }
+                let position = texel.position + texel.normal * normal_offset;
+                visible[i] += cos * shadow_map.visibility(position);
+                weight[i] += cos;
+            }
+        }
+        Ok((visible, weight))
+    }
+}
+
+/// 遮蔽系数转成灰度图，第0行是v最小的一行
+pub fn to_luma8(occlusion: &Plane<f32>) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(occlusion.width(), occlusion.height(), |x, y| {
+        Luma([(occlusion.get(x, y).clamp(0., 1.) * 255.).round() as u8])
+    })
+}
+
+/// 保存为灰度图，和 model::load_texture 的约定一致，保存前上下翻转
+pub fn save<P: AsRef<Path>>(occlusion: &Plane<f32>, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let mut image = to_luma8(occlusion);
+    flip_vertical_in_place(&mut image);
+    image.save(path).map_err(|source| RenderError::Save {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// 在uv空间光栅化所有三角形，得到每个被覆盖纹素的位置和法线
+//
+// 顶点没有法线时使用面法线，多个三角形覆盖同一个纹素时后面的覆盖前面的
+fn rasterize_uv(meshes: &[&Mesh], width: u32, height: u32) -> Vec<Texel> {
+    let mut texels: Plane<Option<Texel>> = Plane::new(width, height, None);
+    let (w, h) = (width as f32, height as f32);
+    for mesh in meshes {
+        for face in mesh.indices.chunks_exact(3) {
+            let verts = [0, 1, 2].map(|j| mesh.vertices[face[j] as usize]);
+            let pos = verts.map(|v| *Vec3::from_array(&v.position));
+            let uv = verts.map(|v| glm::vec3(v.texture[0] * w, v.texture[1] * h, 0.));
+            let face_normal = glm::cross(pos[1] - pos[0], pos[2] - pos[0]);
+            let normals = verts.map(|v| {
+                let n = *Vec3::from_array(&v.normal);
+                if glm::length(n) > f32::EPSILON {
+                    n
+                } else {
+                    face_normal
+                }
+            });
+            let max = (width as i32 - 1, height as i32 - 1);
+            edge::for_each_pixel(uv, (0, 0), max, |x, y, bar| {
+                let position = pos[0] * bar.x + pos[1] * bar.y + pos[2] * bar.z;
+                let normal = normals[0] * bar.x + normals[1] * bar.y + normals[2] * bar.z;
+                if glm::length(normal) <= f32::EPSILON {
+                    return;
+                }
+                let index = (x as u32 + y as u32 * width) as usize;
+                let normal = glm::normalize(normal);
+                let texel = Texel {
+                    index,
+                    position,
+                    normal,
+                };
+                texels.set(x as u32, y as u32, Some(texel));
+            });
+        }
+    }
+    texels.as_slice().iter().flatten().copied().collect()
+}
+
+// 没有覆盖的纹素取相邻已覆盖纹素的平均值，每轮向外扩展一圈
+fn dilate(occlusion: &mut Plane<f32>, covered: &mut Plane<bool>, padding: u32) {
+    let (width, height) = (occlusion.width() as i32, occlusion.height() as i32);
+    for _ in 0..padding {
+        let (prev, prev_covered) = (occlusion.clone(), covered.clone());
+        for y in 0..height {
+            for x in 0..width {
+                if prev_covered.get(x as u32, y as u32) {
+                    continue;
+                }
+                let (mut sum, mut count) = (0., 0);
+                for qy in (y - 1).max(0)..=(y + 1).min(height - 1) {
+                    for qx in (x - 1).max(0)..=(x + 1).min(width - 1) {
+                        if prev_covered.get(qx as u32, qy as u32) {
+                            sum += prev.get(qx as u32, qy as u32);
+                            count += 1;
+                        }
+                    }
+                }
+                if count > 0 {
+                    occlusion.set(x as u32, y as u32, sum / count as f32);
+                    covered.set(x as u32, y as u32, true);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 四个顶点组成的矩形，uv按顶点顺序给出
+    fn quad(positions: [[f32; 3]; 4], normal: [f32; 3], uv: [[f32; 2]; 4]) -> Mesh {
+        Mesh {
+            name: None,
+            vertices: (0..4)
+                .map(|i| TexturedVertex {
+                    position: positions[i],
+                    normal,
+                    texture: [uv[i][0], uv[i][1], 0.],
+                })
+                .collect(),
+            indices: vec![0, 1, 2, 0, 2, 3],
+        }
+    }
+
+    fn bake(meshes: &[&Mesh], width: u32, height: u32) -> Plane<f32> {
+        let baker = AoBake {
+            directions: 64,
+            resolution: 128,
+            ..Default::default()
+        };
+        baker.bake(meshes, width, height).unwrap()
+    }
+
+    #[test]
+    fn flat_plane_is_not_occluded() {
+        let floor = quad(
+            [[0., 0., 0.], [1., 0., 0.], [1., 0., -1.], [0., 0., -1.]],
+            [0., 1., 0.],
+            [[0., 0.], [1., 0.], [1., 1.], [0., 1.]],
+        );
+        let occlusion = bake(&[&floor], 8, 8);
+        for &ao in occlusion.as_slice() {
+            assert!(ao > 0.99, "{}", ao);
+        }
+    }
+
+    #[test]
+    fn concave_corner_is_occluded() {
+        // 地面和墙组成的直角，地面占uv的左半边，墙占右半边，u=0和u=0.5都在墙角处
+        let floor = quad(
+            [[0., 0., 0.], [1., 0., 0.], [1., 0., -1.], [0., 0., -1.]],
+            [0., 1., 0.],
+            [[0., 0.], [0.5, 0.], [0.5, 1.], [0., 1.]],
+        );
+        let wall = quad(
+            [[0., 0., 0.], [0., 1., 0.], [0., 1., -1.], [0., 0., -1.]],
+            [1., 0., 0.],
+            [[0.5, 0.], [1., 0.], [1., 1.], [0.5, 1.]],
+        );
+        let occlusion = bake(&[&floor, &wall], 16, 4);
+        let (corner, far) = (occlusion.get(0, 2), occlusion.get(7, 2));
+        // 墙角处接近一半的半球被墙挡住，离墙越远越亮
+        assert!(corner < 0.8, "{}", corner);
+        assert!(far - corner > 0.15, "{} {}", corner, far);
+        assert!(occlusion.get(8, 2) < occlusion.get(15, 2));
+        for &ao in occlusion.as_slice() {
+            assert!((0. ..=1.).contains(&ao), "{}", ao);
+        }
+    }
+}
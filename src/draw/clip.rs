@@ -0,0 +1,182 @@
+use glm::Vec4;
+
+/// 裁剪后多边形的顶点
+#[derive(Debug, Clone, Copy)]
+pub struct ClipVertex {
+    /// 裁剪空间坐标(齐次坐标)
+    pub pos: Vec4,
+    /// 相对原三角形三个顶点的重心坐标
+    ///
+    /// 新生成的顶点是原三角形顶点的线性组合，着色器用它插值varying就能得到一致的值
+    pub bar: glm::Vec3,
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            pos: self.pos + (other.pos - self.pos) * t,
+            bar: self.bar + (other.bar - self.bar) * t,
+        }
+    }
+}
+
+// 视锥体的六个面，齐次坐标下 -w <= x,y,z <= w
+// 返回值 >= 0 表示在平面内侧
+const PLANES: [fn(&Vec4) -> f32; 6] = [
+    |v| v.w + v.x,
+    |v| v.w - v.x,
+    |v| v.w + v.y,
+    |v| v.w - v.y,
+    |v| v.w + v.z,
+    |v| v.w - v.z,
+];
+
+fn inside_all(v: &Vec4) -> bool {
+    PLANES.iter().all(|plane| plane(v) >= 0.)
+}
+
+/// Sutherland–Hodgman 算法，在齐次裁剪空间中用视锥体六个面裁剪三角形
+///
+/// 返回裁剪后的凸多边形顶点(按原三角形的环绕顺序)，顶点数小于3表示三角形被完全裁掉
+pub fn clip_triangle(clip_coords: [Vec4; 3]) -> Vec<ClipVertex> {
+    let mut polygon: Vec<ClipVertex> = clip_coords
+        .iter()
+        .zip([
+            glm::vec3(1., 0., 0.),
+            glm::vec3(0., 1., 0.),
+            glm::vec3(0., 0., 1.),
+        ])
+        .map(|(&pos, bar)| ClipVertex { pos, bar })
+        .collect();
+
+    // 大部分三角形完全在视锥体内，直接返回
+    if clip_coords.iter().all(inside_all) {
+        return polygon;
+    }
+
+    for plane in PLANES {
+        if polygon.len() < 3 {
+            break;
+        }
+        let mut output = Vec::with_capacity(polygon.len() + 1);
+        for i in 0..polygon.len() {
+            let cur = &polygon[i];
+            let next = &polygon[(i + 1) % polygon.len()];
+            let (d_cur, d_next) = (plane(&cur.pos), plane(&next.pos));
+            if d_cur >= 0. {
+                output.push(*cur);
+            }
+            // 边跨过平面，在交点处生成新顶点
+            if (d_cur >= 0.) != (d_next >= 0.) {
+                output.push(cur.lerp(next, d_cur / (d_cur - d_next)));
+            }
+        }
+        polygon = output;
+    }
+    polygon
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 用重心坐标组合原三角形的顶点
+    fn from_bar(tri: &[Vec4; 3], bar: glm::Vec3) -> Vec4 {
+        tri[0] * bar.x + tri[1] * bar.y + tri[2] * bar.z
+    }
+
+    fn assert_near(actual: Vec4, expected: Vec4) {
+        let d = actual - expected;
+        assert!(
+            d.x.abs() < 1e-5 && d.y.abs() < 1e-5 && d.z.abs() < 1e-5 && d.w.abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    // 裁剪结果都在视锥体内，并且重心坐标能还原出顶点位置
+    fn check(tri: [Vec4; 3], polygon: &[ClipVertex]) {
+        for v in polygon {
+            assert!(PLANES.iter().all(|plane| plane(&v.pos) >= -1e-5), "{:?}", v);
+            assert!((v.bar.x + v.bar.y + v.bar.z - 1.).abs() < 1e-5, "{:?}", v);
+            assert_near(from_bar(&tri, v.bar), v.pos);
+        }
+    }
+
+    #[test]
+    fn inside_triangle_is_unchanged() {
+        let tri = [
+            glm::vec4(-0.5, -0.5, 0., 1.),
+            glm::vec4(0.5, -0.5, 0.2, 1.),
+            glm::vec4(0., 0.5, -0.2, 1.),
+        ];
+        let polygon = clip_triangle(tri);
+        assert_eq!(polygon.len(), 3);
+        for (v, p) in polygon.iter().zip(tri) {
+            assert_eq!(v.pos, p);
+        }
+        check(tri, &polygon);
+    }
+
+    #[test]
+    fn outside_triangle_is_removed() {
+        // 整个三角形在右侧平面之外
+        let tri = [
+            glm::vec4(2., -0.5, 0., 1.),
+            glm::vec4(3., -0.5, 0., 1.),
+            glm::vec4(2.5, 0.5, 0., 1.),
+        ];
+        assert!(clip_triangle(tri).len() < 3);
+        // 整个三角形在摄像机后面
+        let tri = [
+            glm::vec4(0., 0., 1., -1.),
+            glm::vec4(0.5, 0., 2., -2.),
+            glm::vec4(0., 0.5, 1., -1.),
+        ];
+        assert!(clip_triangle(tri).len() < 3);
+    }
+
+    #[test]
+    fn near_plane_cut_yields_quad() {
+        // 第一个顶点在近平面之外，w为0
+        let tri = [
+            glm::vec4(0., 0., -1., 0.),
+            glm::vec4(-0.5, 0., 0.5, 1.),
+            glm::vec4(0.5, 0., 0.5, 1.),
+        ];
+        let polygon = clip_triangle(tri);
+        assert_eq!(polygon.len(), 4);
+        check(tri, &polygon);
+        for v in &polygon {
+            assert!(v.pos.w > 0., "{:?}", v);
+        }
+        // 新顶点正好在近平面上
+        let on_near = polygon
+            .iter()
+            .filter(|v| (v.pos.w + v.pos.z).abs() < 1e-5)
+            .count();
+        assert_eq!(on_near, 2);
+    }
+
+    #[test]
+    fn clipped_vertices_keep_winding_and_barycentrics() {
+        // 超出所有侧面的大三角形
+        let tri = [
+            glm::vec4(-3., -2., 0.5, 1.),
+            glm::vec4(3., -2., -0.5, 1.),
+            glm::vec4(0., 4., 0., 1.),
+        ];
+        let polygon = clip_triangle(tri);
+        assert!(polygon.len() > 3);
+        check(tri, &polygon);
+        // 在屏幕上的环绕方向和原三角形一致(逆时针)
+        let area: f32 = (0..polygon.len())
+            .map(|i| {
+                let (a, b) = (polygon[i].pos, polygon[(i + 1) % polygon.len()].pos);
+                a.x / a.w * b.y / b.w - b.x / b.w * a.y / a.w
+            })
+            .sum();
+        assert!(area > 0.);
+    }
+}
@@ -0,0 +1,292 @@
+//! 延迟着色
+//!
+//! 第一遍用 GBufferShader 把表面属性写入G-buffer，每个输出一个浮点缓冲，
+//! 第二遍对每个像素只计算一次光照，不受遮挡的片段数量影响
+
+use glm::Vec3;
+use image::{ImageBuffer, Rgba};
+
+use super::{
+    shadow::ShadowedLights,
+    target::{Plane, RenderTarget},
+};
+
+type Image = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+/// 写入G-buffer的一个像素，是 GBufferShader 的片段输出
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GBufferTexel {
+    pub albedo: Vec3,     // 漫反射颜色 [0,1]
+    pub normal: Vec3,     // 世界坐标的单位法线
+    pub position: Vec3,   // 世界坐标
+    pub specular: f32,    // 高光指数
+    pub material_id: u32, // 0表示没有几何体
+}
+
+/// 几何缓冲，每个输出存在单独的缓冲里
+#[derive(Debug, Clone)]
+pub struct GBuffer {
+    albedo: Plane<Vec3>,
+    normal: Plane<Vec3>,
+    position: Plane<Vec3>,
+    specular: Plane<f32>,
+    material_id: Plane<u32>,
+}
+
+impl GBuffer {
+    /// 创建后所有像素的material_id为0
+    pub fn new(width: u32, height: u32) -> Self {
+        let zero = glm::vec3(0., 0., 0.);
+        Self {
+            albedo: Plane::new(width, height, zero),
+            normal: Plane::new(width, height, zero),
+            position: Plane::new(width, height, zero),
+            specular: Plane::new(width, height, 0.),
+            material_id: Plane::new(width, height, 0),
+        }
+    }
+
+    pub fn albedo(&self) -> &Plane<Vec3> {
+        &self.albedo
+    }
+
+    pub fn normal(&self) -> &Plane<Vec3> {
+        &self.normal
+    }
+
+    pub fn position(&self) -> &Plane<Vec3> {
+        &self.position
+    }
+
+    pub fn specular(&self) -> &Plane<f32> {
+        &self.specular
+    }
+
+    pub fn material_id(&self) -> &Plane<u32> {
+        &self.material_id
+    }
+
+    /// (x,y)处的所有输出，没有几何体时返回None
+    pub fn texel(&self, x: u32, y: u32) -> Option<GBufferTexel> {
+        let material_id = self.material_id.get(x, y);
+        if material_id == 0 {
+            return None;
+        }
+        Some(GBufferTexel {
+            albedo: self.albedo.get(x, y),
+            normal: self.normal.get(x, y),
+            position: self.position.get(x, y),
+            specular: self.specular.get(x, y),
+            material_id,
+        })
+    }
+
+    /// 清空为没有几何体
+    pub fn clear(&mut self) {
+        let zero = glm::vec3(0., 0., 0.);
+        self.albedo.fill(zero);
+        self.normal.fill(zero);
+        self.position.fill(zero);
+        self.specular.fill(0.);
+        self.material_id.fill(0);
+    }
+}
+
+impl RenderTarget for GBuffer {
+    type Value = GBufferTexel;
+
+    fn width(&self) -> u32 {
+        self.material_id.width()
+    }
+
+    fn height(&self) -> u32 {
+        self.material_id.height()
+    }
+
+    fn put(&mut self, x: u32, y: u32, value: GBufferTexel) {
+        self.albedo.set(x, y, value.albedo);
+        self.normal.set(x, y, value.normal);
+        self.position.set(x, y, value.position);
+        self.specular.set(x, y, value.specular);
+        self.material_id.set(x, y, value.material_id);
+    }
+
+    fn region(&self, x: u32, y: u32, w: u32, h: u32) -> Self {
+        Self {
+            albedo: self.albedo.region(x, y, w, h),
+            normal: self.normal.region(x, y, w, h),
+            position: self.position.region(x, y, w, h),
+            specular: self.specular.region(x, y, w, h),
+            material_id: self.material_id.region(x, y, w, h),
+        }
+    }
+
+    fn copy_from(&mut self, src: &Self, x: u32, y: u32) {
+        self.albedo.copy_from(&src.albedo, x, y);
+        self.normal.copy_from(&src.normal, x, y);
+        self.position.copy_from(&src.position, x, y);
+        self.specular.copy_from(&src.specular, x, y);
+        self.material_id.copy_from(&src.material_id, x, y);
+    }
+}
+
+/// 对G-buffer中的每个像素计算一次光照，写入image
+///
+/// eye是世界坐标的摄像机位置，光照和阴影的计算和 PhongShader 相同，没有几何体的像素保持不变
+///
+/// G-buffer由 Renderer::draw_mesh_to 填充，它不支持多重采样，所以image传 Renderer::image_mut 即可
+///
+/// ambient_occlusion是每个像素的环境光遮蔽系数，见 ssao::Ssao
+pub fn shade(
+    gbuffer: &GBuffer,
+    lights: &ShadowedLights,
+    eye: Vec3,
+    ambient_occlusion: Option<&Plane<f32>>,
+    image: &mut Image,
+) {
+    let arg_specular = 0.6; // 镜面反射光
+    for y in 0..gbuffer.height() {
+        for x in 0..gbuffer.width() {
+            let texel = match gbuffer.texel(x, y) {
+                Some(texel) => texel,
+                None => continue,
+            };
+            let arg_ambient = 5. * ambient_occlusion.map_or(1., |ao| ao.get(x, y)); // 环境光
+            let n = texel.normal;
+            let v = glm::normalize(eye - texel.position);
+            let mut light = glm::vec3(0., 0., 0.);
+            for (source, shadow) in lights.iter() {
+                let (l, radiance) = source.illuminate(texel.position);
+                let r = glm::normalize(n * (glm::dot(n, l) * 2.) - l); // 反射光方向
+                let spec = glm::pow(glm::dot(v, r).max(0.), texel.specular);
+                let diff = glm::dot(n, l).max(0.);
+                // 阴影中的像素保留30%的光照
+                let shadow = match shadow {
+                    Some(shadow) => 0.3 + 0.7 * shadow.visibility(texel.position),
+                    None => 1.,
+                };
+                light = light + radiance * (shadow * (diff + arg_specular * spec));
+            }
+            let c = texel.albedo * light * 255. + arg_ambient;
+            image.put_pixel(x, y, Rgba([c.x as u8, c.y as u8, c.z as u8, 255]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glm::Mat4;
+    use num::One;
+    use obj::TexturedVertex;
+
+    use super::*;
+    use crate::{
+        draw::{light::Light, shadow::ShadowMap, texture::Texture},
+        GBufferShader, PhongShader, Renderer,
+    };
+
+    const SIZE: u32 = 64;
+
+    fn solid(color: [u8; 3]) -> Texture {
+        let [r, g, b] = color;
+        Texture::new(ImageBuffer::from_pixel(1, 1, Rgba([r, g, b, 255])))
+    }
+
+    // 朝向+z的多边形，按扇形分成三角形
+    fn polygon(positions: &[[f32; 3]]) -> obj::Obj<TexturedVertex, u32> {
+        obj::Obj {
+            name: None,
+            vertices: positions
+                .iter()
+                .map(|&position| TexturedVertex {
+                    position,
+                    normal: [0., 0., 1.],
+                    texture: [position[0] * 0.5 + 0.5, position[1] * 0.5 + 0.5, 0.],
+                })
+                .collect(),
+            indices: (1..positions.len() as u32 - 1)
+                .flat_map(|i| [0, i, i + 1])
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn matches_forward_phong_with_shadows() {
+        // 地面上方悬空一个小三角形，斜向的平行光把它的影子投到地面左侧
+        let meshes = [
+            polygon(&[
+                [-0.9, -0.9, 0.],
+                [0.9, -0.9, 0.],
+                [0.9, 0.9, 0.],
+                [-0.9, 0.9, 0.],
+            ]),
+            polygon(&[[-0.15, -0.15, 0.5], [0.15, -0.15, 0.5], [0., 0.2, 0.5]]),
+        ];
+        let (diffuse, normal, specular) = (
+            solid([200, 180, 160]),
+            solid([128, 128, 255]),
+            solid([20, 20, 20]),
+        );
+        let light = Light::directional(glm::vec3(1., 0., 1.));
+        let eye = glm::vec3(0.3, 0.2, 3.);
+
+        let mut shadow_map = ShadowMap::for_light(
+            &light,
+            glm::vec3(0., 0., 0.25),
+            1.5,
+            SIZE,
+            SIZE,
+            ShadowMap::DEFAULT_BIAS,
+        );
+        for mesh in &meshes {
+            shadow_map.render(mesh).unwrap();
+        }
+
+        let mut forward = Renderer::new(SIZE, SIZE);
+        for mesh in &meshes {
+            let shader = PhongShader::new(
+                mesh,
+                &diffuse,
+                &normal,
+                &specular,
+                Mat4::one(),
+                Mat4::one(),
+                eye,
+                &[light],
+            )
+            .unwrap()
+            .with_shadow(&shadow_map);
+            forward.draw_mesh(mesh, &shader).unwrap();
+        }
+
+        let mut deferred = Renderer::new(SIZE, SIZE);
+        let mut gbuffer = GBuffer::new(SIZE, SIZE);
+        for mesh in &meshes {
+            let shader =
+                GBufferShader::new(mesh, &diffuse, &normal, &specular, Mat4::one(), Mat4::one())
+                    .unwrap();
+            deferred.draw_mesh_to(mesh, &shader, &mut gbuffer).unwrap();
+        }
+        let mut lights = ShadowedLights::new(&[light]);
+        lights.set_shadow(0, &shadow_map);
+        shade(&gbuffer, &lights, eye, None, deferred.image_mut());
+
+        for (x, y, f) in forward.image().enumerate_pixels() {
+            let d = deferred.image().get_pixel(x, y);
+            assert!(
+                f.0.iter().zip(&d.0).all(|(f, d)| f.abs_diff(*d) <= 1),
+                "({}, {}) forward {:?} deferred {:?}",
+                x,
+                y,
+                f,
+                d
+            );
+        }
+        // 地面上 x=-0.5 处在影子里，x=0.5 处被照亮
+        let (shadowed, lit) = (
+            deferred.image().get_pixel(16, 32),
+            deferred.image().get_pixel(48, 32),
+        );
+        assert!(shadowed.0[0] + 40 < lit.0[0], "{:?} {:?}", shadowed, lit);
+    }
+}
@@ -0,0 +1,289 @@
+use image::{ImageBuffer, Luma};
+use num::Float;
+
+/// 深度比较函数，比较的是 新片段深度 与 缓冲中已有深度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DepthFunc {
+    /// 永不通过
+    Never,
+    /// 新深度 < 旧深度 时通过
+    #[default]
+    Less,
+    /// 新深度 <= 旧深度 时通过
+    LessEqual,
+    /// 新深度 > 旧深度 时通过
+    Greater,
+    /// 新深度 >= 旧深度 时通过
+    GreaterEqual,
+    /// 总是通过
+    Always,
+}
+
+impl DepthFunc {
+    pub fn compare<T: PartialOrd>(self, new: T, old: T) -> bool {
+        match self {
+            DepthFunc::Never => false,
+            DepthFunc::Less => new < old,
+            DepthFunc::LessEqual => new <= old,
+            DepthFunc::Greater => new > old,
+            DepthFunc::GreaterEqual => new >= old,
+            DepthFunc::Always => true,
+        }
+    }
+}
+
+/// 浮点深度缓冲，T 可以是 f32 或 f64
+///
+/// 深度值约定在 [0,1] 范围内，与 viewport 的深度映射一致
+#[derive(Debug, Clone)]
+pub struct DepthBuffer<T = f32> {
+    width: u32,
+    height: u32,
+    data: Vec<T>,
+    func: DepthFunc,
+    write_enabled: bool,
+    clear_value: T,
+}
+
+impl<T: Float> DepthBuffer<T> {
+    pub fn new(width: u32, height: u32, func: DepthFunc, clear_value: T) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![clear_value; (width * height) as usize],
+            func,
+            write_enabled: true,
+            clear_value,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn func(&self) -> DepthFunc {
+        self.func
+    }
+
+    pub fn set_func(&mut self, func: DepthFunc) {
+        self.func = func;
+    }
+
+    pub fn write_enabled(&self) -> bool {
+        self.write_enabled
+    }
+
+    /// 关闭后深度测试照常进行，但不再更新缓冲
+    pub fn set_write_enabled(&mut self, enabled: bool) {
+        self.write_enabled = enabled;
+    }
+
+    pub fn clear_value(&self) -> T {
+        self.clear_value
+    }
+
+    pub fn set_clear_value(&mut self, clear_value: T) {
+        self.clear_value = clear_value;
+    }
+
+    /// 用清除值填满整个缓冲
+    pub fn clear(&mut self) {
+        let v = self.clear_value;
+        self.data.iter_mut().for_each(|d| *d = v);
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (x + y * self.width) as usize
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> T {
+        self.data[self.index(x, y)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, depth: T) {
+        let idx = self.index(x, y);
+        self.data[idx] = depth;
+    }
+
+    /// 只做深度测试，不写入
+    pub fn test(&self, x: u32, y: u32, depth: T) -> bool {
+        self.func.compare(depth, self.get(x, y))
+    }
+
+    /// 允许写入时更新缓冲，用于先 test 再决定是否写入的情况
+    pub fn write(&mut self, x: u32, y: u32, depth: T) {
+        if self.write_enabled {
+            self.set(x, y, depth);
+        }
+    }
+
+    /// 深度测试，通过并且允许写入时更新缓冲
+    ///
+    /// 返回是否通过测试
+    pub fn test_and_set(&mut self, x: u32, y: u32, depth: T) -> bool {
+        let idx = self.index(x, y);
+        if !self.func.compare(depth, self.data[idx]) {
+            return false;
+        }
+        if self.write_enabled {
+            self.data[idx] = depth;
+        }
+        true
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    /// 复制出(x,y)开始的w*h区域，深度测试设置保持不变
+    pub fn region(&self, x: u32, y: u32, w: u32, h: u32) -> DepthBuffer<T> {
+        let mut data = Vec::with_capacity((w * h) as usize);
+        for row in y..y + h {
+            let start = self.index(x, row);
+            data.extend_from_slice(&self.data[start..start + w as usize]);
+        }
+        DepthBuffer {
+            width: w,
+            height: h,
+            data,
+            ..*self
+        }
+    }
+
+    /// 把src写回到(x,y)开始的区域，和region配合使用
+    pub fn copy_from(&mut self, src: &DepthBuffer<T>, x: u32, y: u32) {
+        let w = src.width as usize;
+        for row in 0..src.height {
+            let start = self.index(x, y + row);
+            let src_start = (row * src.width) as usize;
+            self.data[start..start + w].copy_from_slice(&src.data[src_start..src_start + w]);
+        }
+    }
+
+    // [0,1] 映射到 [0,max]，超出范围的截断
+    fn normalized(&self, max: f64) -> impl Iterator<Item = f64> + '_ {
+        self.data.iter().map(move |d| {
+            let d = d.to_f64().unwrap_or(0.);
+            if d.is_nan() {
+                0.
+            } else {
+                (d.clamp(0., 1.) * max).round()
+            }
+        })
+    }
+
+    /// 导出为8位灰度图用于查看
+    pub fn to_luma8(&self) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        let data = self.normalized(u8::MAX as f64).map(|d| d as u8).collect();
+        ImageBuffer::from_raw(self.width, self.height, data).unwrap()
+    }
+
+    /// 导出为16位灰度图，精度更高
+    pub fn to_luma16(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let data = self.normalized(u16::MAX as f64).map(|d| d as u16).collect();
+        ImageBuffer::from_raw(self.width, self.height, data).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_and_set_follows_func() {
+        // (比较函数, 通过的新深度) 缓冲中已有深度为0.5
+        let cases = [
+            (DepthFunc::Never, [false, false, false]),
+            (DepthFunc::Less, [true, false, false]),
+            (DepthFunc::LessEqual, [true, true, false]),
+            (DepthFunc::Greater, [false, false, true]),
+            (DepthFunc::GreaterEqual, [false, true, true]),
+            (DepthFunc::Always, [true, true, true]),
+        ];
+        for (func, expected) in cases {
+            for (depth, pass) in [0.25, 0.5, 0.75].into_iter().zip(expected) {
+                let mut buffer = DepthBuffer::new(1, 1, func, 0.5f32);
+                assert_eq!(buffer.test(0, 0, depth), pass, "{:?} {}", func, depth);
+                assert_eq!(
+                    buffer.test_and_set(0, 0, depth),
+                    pass,
+                    "{:?} {}",
+                    func,
+                    depth
+                );
+                let stored = if pass { depth } else { 0.5 };
+                assert_eq!(buffer.get(0, 0), stored, "{:?} {}", func, depth);
+            }
+        }
+    }
+
+    #[test]
+    fn write_disabled_keeps_buffer() {
+        let mut buffer = DepthBuffer::new(1, 1, DepthFunc::Less, 1f64);
+        buffer.set_write_enabled(false);
+        assert!(buffer.test_and_set(0, 0, 0.3));
+        buffer.write(0, 0, 0.3);
+        assert_eq!(buffer.get(0, 0), 1.);
+    }
+
+    #[test]
+    fn clear_value_passes_whole_depth_range() {
+        // 普通投影清空为1，reversed-Z清空为0，[0,1]内的深度都能通过
+        for (func, clear) in [(DepthFunc::LessEqual, 1.), (DepthFunc::GreaterEqual, 0.)] {
+            let mut buffer = DepthBuffer::new(2, 1, func, clear);
+            assert_eq!(buffer.clear_value(), clear);
+            for depth in [0., 0.5, 1.] {
+                assert!(buffer.test(0, 0, depth), "{:?} {}", func, depth);
+            }
+            buffer.set(0, 0, 0.5);
+            buffer.clear();
+            assert_eq!(buffer.as_slice(), [clear, clear]);
+        }
+        let mut buffer = DepthBuffer::new(2, 1, DepthFunc::GreaterEqual, 0f32);
+        buffer.set_clear_value(0.25);
+        buffer.clear();
+        assert_eq!(buffer.as_slice(), [0.25, 0.25]);
+    }
+
+    #[test]
+    fn region_round_trips_through_copy_from() {
+        let mut buffer = DepthBuffer::new(4, 3, DepthFunc::Greater, 0f32);
+        for y in 0..3 {
+            for x in 0..4 {
+                buffer.set(x, y, (x + y * 4) as f32 / 16.);
+            }
+        }
+        let mut region = buffer.region(1, 1, 2, 2);
+        assert_eq!((region.width(), region.height()), (2, 2));
+        assert_eq!(region.func(), DepthFunc::Greater);
+        assert_eq!(region.as_slice(), [5. / 16., 6. / 16., 9. / 16., 10. / 16.]);
+
+        region.set(1, 0, 1.);
+        let before = buffer.clone();
+        buffer.copy_from(&region, 1, 1);
+        for y in 0..3 {
+            for x in 0..4 {
+                let expected = if (x, y) == (2, 1) {
+                    1.
+                } else {
+                    before.get(x, y)
+                };
+                assert_eq!(buffer.get(x, y), expected, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn export_clamps_to_range() {
+        let mut buffer = DepthBuffer::new(4, 1, DepthFunc::Less, 0f32);
+        for (x, d) in [-1., 0.5, 1., f32::NAN].into_iter().enumerate() {
+            buffer.set(x as u32, 0, d);
+        }
+        assert_eq!(buffer.to_luma8().into_raw(), [0, 128, 255, 0]);
+        assert_eq!(buffer.to_luma16().into_raw(), [0, 32768, 65535, 0]);
+    }
+}
@@ -0,0 +1,216 @@
+//! 边函数光栅化
+//!
+//! 顶点坐标转换成定点数，在像素中心处增量计算三条边的边函数，
+//! 三个值都在内侧的像素属于三角形，三个值除以面积就是屏幕空间的重心坐标
+//!
+//! 像素正好落在边上时按左上规则归属，相邻三角形的公共边不会重复绘制也不会留缝
+
+use glm::Vec3;
+
+/// 定点数的小数位数，1/256像素精度
+pub const SUBPIXEL_BITS: u32 = 8;
+const SUBPIXEL: i64 = 1 << SUBPIXEL_BITS;
+const HALF_PIXEL: i64 = SUBPIXEL / 2;
+
+fn to_fixed(v: f32) -> i64 {
+    (v * SUBPIXEL as f32).round() as i64
+}
+
+// 边a->b在p处的边函数，p在边左侧(逆时针内侧)时为正
+fn edge(a: (i64, i64), b: (i64, i64), p: (i64, i64)) -> i64 {
+    (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+}
+
+// 左上规则，屏幕坐标y向上，三角形为逆时针
+// 左边向下走，上边水平并向左走，这两种边上的像素算在三角形内
+fn is_top_left(a: (i64, i64), b: (i64, i64)) -> bool {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    dy < 0 || (dy == 0 && dx < 0)
+}
+
+/// 三角形在屏幕上的有向面积的两倍(定点数)，逆时针为正
+///
+/// 和for_each_pixel使用相同的定点坐标，为0时for_each_pixel不会覆盖任何像素
+pub fn signed_area(v: [Vec3; 3]) -> i64 {
+    let p = v.map(|v| (to_fixed(v.x), to_fixed(v.y)));
+    edge(p[0], p[1], p[2])
+}
+
+/// 屏幕空间重心坐标对x和y的导数，即向右和向上移动一个像素时的变化
+///
+/// 面积为0时返回0
+pub fn barycentric_gradient(v: [Vec3; 3]) -> (Vec3, Vec3) {
+    match Edges::new(v) {
+        Some(e) => {
+            let (dx, dy) = e.gradient();
+            (
+                e.barycentric(&dx.map(|d| d * SUBPIXEL)),
+                e.barycentric(&dy.map(|d| d * SUBPIXEL)),
+            )
+        }
+        None => (glm::vec3(0., 0., 0.), glm::vec3(0., 0., 0.)),
+    }
+}
+
+// 三角形的边函数，统一成逆时针
+struct Edges {
+    // 边i是对着顶点i的边，它的边函数就是顶点i的权重
+    edges: [((i64, i64), (i64, i64)); 3],
+    // 交换后每个位置对应的原顶点
+    order: [usize; 3],
+    // 不是左上边时，正好在边上的点不算
+    bias: [i64; 3],
+    inv_area: f32,
+    // 定点坐标的包围盒
+    min: (i64, i64),
+    max: (i64, i64),
+}
+
+impl Edges {
+    fn new(v: [Vec3; 3]) -> Option<Edges> {
+        let mut p = v.map(|v| (to_fixed(v.x), to_fixed(v.y)));
+        let mut area = edge(p[0], p[1], p[2]);
+        if area == 0 {
+            return None;
+        }
+        let mut order = [0, 1, 2];
+        if area < 0 {
+            p.swap(1, 2);
+            order.swap(1, 2);
+            area = -area;
+        }
+        let edges = [(p[1], p[2]), (p[2], p[0]), (p[0], p[1])];
+        Some(Edges {
+            edges,
+            order,
+            bias: edges.map(|(a, b)| if is_top_left(a, b) { 0 } else { -1 }),
+            inv_area: 1. / area as f32,
+            min: (
+                p[0].0.min(p[1].0).min(p[2].0),
+                p[0].1.min(p[1].1).min(p[2].1),
+            ),
+            max: (
+                p[0].0.max(p[1].0).max(p[2].0),
+                p[0].1.max(p[1].1).max(p[2].1),
+            ),
+        })
+    }
+
+    // 定点坐标p处的边函数
+    fn at(&self, p: (i64, i64)) -> [i64; 3] {
+        self.edges.map(|(a, b)| edge(a, b, p))
+    }
+
+    // 点向x和y方向各移动一个定点单位时边函数的增量
+    fn gradient(&self) -> ([i64; 3], [i64; 3]) {
+        (
+            self.edges.map(|(a, b)| -(b.1 - a.1)),
+            self.edges.map(|(a, b)| b.0 - a.0),
+        )
+    }
+
+    fn inside(&self, w: &[i64; 3]) -> bool {
+        w[0] + self.bias[0] >= 0 && w[1] + self.bias[1] >= 0 && w[2] + self.bias[2] >= 0
+    }
+
+    // 边函数换算成按原顶点顺序的重心坐标
+    fn barycentric(&self, w: &[i64; 3]) -> Vec3 {
+        let mut bc = [0.; 3];
+        for (&o, &w) in self.order.iter().zip(w) {
+            bc[o] = w as f32 * self.inv_area;
+        }
+        glm::vec3(bc[0], bc[1], bc[2])
+    }
+}
+
+// 按行遍历[x0,x1]x[y0,y1]的像素，w是像素中心处的边函数
+fn scan<F: FnMut(i64, i64, &[i64; 3])>(e: &Edges, x0: i64, y0: i64, x1: i64, y1: i64, mut f: F) {
+    if x0 > x1 || y0 > y1 {
+        return;
+    }
+    let (dx, dy) = e.gradient();
+    let mut row = e.at((x0 * SUBPIXEL + HALF_PIXEL, y0 * SUBPIXEL + HALF_PIXEL));
+    for y in y0..=y1 {
+        let mut w = row;
+        for x in x0..=x1 {
+            f(x, y, &w);
+            w.iter_mut().zip(dx).for_each(|(w, d)| *w += d * SUBPIXEL);
+        }
+        row.iter_mut().zip(dy).for_each(|(r, d)| *r += d * SUBPIXEL);
+    }
+}
+
+/// 遍历三角形覆盖的像素，min和max是允许绘制的像素范围(包含)
+///
+/// 按行遍历，对每个像素调用f(x, y, 重心坐标)，重心坐标对应输入顶点的顺序
+///
+/// 面积为0的三角形不覆盖任何像素，两种环绕方向都会绘制
+pub fn for_each_pixel<F: FnMut(i32, i32, Vec3)>(
+    v: [Vec3; 3],
+    min: (i32, i32),
+    max: (i32, i32),
+    mut f: F,
+) {
+    let e = match Edges::new(v) {
+        Some(e) => e,
+        None => return,
+    };
+    // 包围盒内的像素中心 (x+0.5, y+0.5)
+    let lo = |v: i64| (v - HALF_PIXEL + SUBPIXEL - 1) >> SUBPIXEL_BITS;
+    let hi = |v: i64| (v - HALF_PIXEL) >> SUBPIXEL_BITS;
+    let x0 = lo(e.min.0).max(min.0 as i64);
+    let y0 = lo(e.min.1).max(min.1 as i64);
+    let x1 = hi(e.max.0).min(max.0 as i64);
+    let y1 = hi(e.max.1).min(max.1 as i64);
+    scan(&e, x0, y0, x1, y1, |x, y, w| {
+        if e.inside(w) {
+            f(x as i32, y as i32, e.barycentric(w));
+        }
+    });
+}
+
+/// 多重采样版本，offsets是采样点相对像素中心的偏移，单位1/16像素，最多32个
+///
+/// 对至少有一个采样点被覆盖的像素调用f(x, y, 覆盖掩码, 像素中心的重心坐标, 每个采样点的重心坐标)，
+/// 掩码第i位表示第i个采样点被覆盖，像素中心可能在三角形外
+pub fn for_each_pixel_multisample<F: FnMut(i32, i32, u32, Vec3, &[Vec3])>(
+    v: [Vec3; 3],
+    min: (i32, i32),
+    max: (i32, i32),
+    offsets: &[(i32, i32)],
+    mut f: F,
+) {
+    let e = match Edges::new(v) {
+        Some(e) => e,
+        None => return,
+    };
+    // 采样点都在像素内部，包围盒和像素方格相交的像素都要检查
+    let x0 = (e.min.0 >> SUBPIXEL_BITS).max(min.0 as i64);
+    let y0 = (e.min.1 >> SUBPIXEL_BITS).max(min.1 as i64);
+    let x1 = (e.max.0 >> SUBPIXEL_BITS).min(max.0 as i64);
+    let y1 = (e.max.1 >> SUBPIXEL_BITS).min(max.1 as i64);
+    // 每个采样点相对像素中心的边函数增量
+    let (dx, dy) = e.gradient();
+    let unit = SUBPIXEL / 16;
+    let deltas: Vec<[i64; 3]> = offsets
+        .iter()
+        .map(|&(ox, oy)| {
+            let (ox, oy) = (ox as i64 * unit, oy as i64 * unit);
+            [0, 1, 2].map(|i| dx[i] * ox + dy[i] * oy)
+        })
+        .collect();
+    let mut bcs = vec![glm::vec3(0., 0., 0.); offsets.len()];
+    scan(&e, x0, y0, x1, y1, |x, y, w| {
+        let mut mask = 0;
+        for (s, delta) in deltas.iter().enumerate() {
+            let ws = [w[0] + delta[0], w[1] + delta[1], w[2] + delta[2]];
+            if e.inside(&ws) {
+                mask |= 1 << s;
+                bcs[s] = e.barycentric(&ws);
+            }
+        }
+        if mask != 0 {
+            f(x as i32, y as i32, mask, e.barycentric(w), &bcs);
+        }
+    });
+}
@@ -0,0 +1,301 @@
+//! 光源
+//!
+//! 平行光、点光源和聚光灯，着色器对每个光源分别计算光照再累加，
+//! 投射阴影的光源可以各自带一张 shadow::ShadowMap
+
+use glm::{Mat4, Vec3};
+use num::One;
+
+use crate::{any_perpendicular, smoothstep};
+
+use super::{lookat, orthographic, perspective, reversed_z};
+
+/// 点光源和聚光灯的距离衰减 1/(constant + linear*d + quadratic*d^2)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self {
+            constant: 1.,
+            linear: 0.,
+            quadratic: 1.,
+        }
+    }
+}
+
+impl Attenuation {
+    /// 不随距离衰减
+    pub const NONE: Attenuation = Attenuation {
+        constant: 1.,
+        linear: 0.,
+        quadratic: 0.,
+    };
+
+    /// 距离d处的衰减系数
+    pub fn factor(&self, d: f32) -> f32 {
+        let denom = self.constant + self.linear * d + self.quadratic * d * d;
+        if denom > f32::EPSILON {
+            1. / denom
+        } else {
+            1.
+        }
+    }
+}
+
+/// 光源，位置和方向都是世界坐标，color乘上intensity是光源的辐射强度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Light {
+    /// 平行光，direction指向光源
+    Directional {
+        direction: Vec3,
+        color: Vec3,
+        intensity: f32,
+    },
+    /// 点光源，向所有方向发光，按距离衰减
+    Point {
+        position: Vec3,
+        color: Vec3,
+        intensity: f32,
+        attenuation: Attenuation,
+    },
+    /// 聚光灯，direction是光照射的方向
+    ///
+    /// inner_angle以内全亮，outer_angle以外不亮，中间平滑过渡，都是和direction的夹角(弧度)
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        color: Vec3,
+        intensity: f32,
+        attenuation: Attenuation,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+}
+
+impl Light {
+    /// 白色、强度为1的平行光，和原来只有light_dir时的光照一致
+    pub fn directional(direction: Vec3) -> Self {
+        Light::Directional {
+            direction,
+            color: glm::vec3(1., 1., 1.),
+            intensity: 1.,
+        }
+    }
+
+    /// 白色、强度为1、按默认方式衰减的点光源
+    pub fn point(position: Vec3) -> Self {
+        Light::Point {
+            position,
+            color: glm::vec3(1., 1., 1.),
+            intensity: 1.,
+            attenuation: Attenuation::default(),
+        }
+    }
+
+    /// 白色、强度为1、按默认方式衰减的聚光灯
+    pub fn spot(position: Vec3, direction: Vec3, inner_angle: f32, outer_angle: f32) -> Self {
+        Light::Spot {
+            position,
+            direction,
+            color: glm::vec3(1., 1., 1.),
+            intensity: 1.,
+            attenuation: Attenuation::default(),
+            inner_angle,
+            outer_angle,
+        }
+    }
+
+    /// 世界坐标pos处指向光源的单位向量，以及到达pos的光(颜色*强度*衰减)
+    pub fn illuminate(&self, pos: Vec3) -> (Vec3, Vec3) {
+        match *self {
+            Light::Directional {
+                direction,
+                color,
+                intensity,
+            } => (glm::normalize(direction), color * intensity),
+            Light::Point {
+                position,
+                color,
+                intensity,
+                attenuation,
+            } => {
+                let (l, d) = to_light(position, pos);
+                (l, color * (intensity * attenuation.factor(d)))
+            }
+            Light::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                attenuation,
+                inner_angle,
+                outer_angle,
+            } => {
+                let (l, d) = to_light(position, pos);
+                let cos = glm::dot(-l, glm::normalize(direction));
+                let (cos_inner, cos_outer) = (inner_angle.cos(), outer_angle.cos());
+                let cone = if cos_inner - cos_outer > f32::EPSILON {
+                    smoothstep((cos - cos_outer) / (cos_inner - cos_outer))
+                } else if cos >= cos_outer {
+                    1.
+                } else {
+                    0.
+                };
+                (l, color * (intensity * attenuation.factor(d) * cone))
+            }
+        }
+    }
+
+    /// 从光源渲染阴影贴图用的 (model_view, projection)，投影已经是reversed-z的，
+    /// 和 shadow::ShadowMap 的深度测试一致
+    ///
+    /// center和radius是需要投射阴影的物体的包围球，平行光用正交投影正好包住它，
+    /// 点光源用透视投影看向center，聚光灯沿自己的方向看，视角覆盖外锥角
+    pub fn shadow_view(&self, center: Vec3, radius: f32) -> (Mat4, Mat4) {
+        let radius = radius.max(f32::EPSILON);
+        match *self {
+            Light::Directional { direction, .. } => {
+                let dir = glm::normalize(direction);
+                let model_view = lookat(center + dir, center, any_perpendicular(dir));
+                let projection = orthographic(-radius, radius, -radius, radius, -radius, radius);
+                (model_view, reversed_z(projection))
+            }
+            Light::Point { position, .. } => {
+                let dist = glm::length(center - position);
+                // 光源在包围球外时视角正好包住球，在里面时只能看到一部分
+                let fov = if dist > radius {
+                    2. * (radius / dist).asin()
+                } else {
+                    120f32.to_radians()
+                };
+                let target = if dist > f32::EPSILON {
+                    center
+                } else {
+                    position - glm::vec3(0., 0., 1.)
+                };
+                perspective_view(position, target, fov, dist, radius)
+            }
+            Light::Spot {
+                position,
+                direction,
+                outer_angle,
+                ..
+            } => {
+                let dist = glm::length(center - position);
+                let fov = (2. * outer_angle).min(170f32.to_radians());
+                let target = position + glm::normalize(direction);
+                perspective_view(position, target, fov, dist, radius)
+            }
+        }
+    }
+}
+
+// 从eye看向target的透视投影，深度范围覆盖到center距离为dist、半径为radius的包围球
+fn perspective_view(eye: Vec3, target: Vec3, fov: f32, dist: f32, radius: f32) -> (Mat4, Mat4) {
+    let forward = glm::normalize(target - eye);
+    // lookat把摄像机放在target处，再平移到eye
+    let model_view = lookat(eye, target, any_perpendicular(forward))
+        * glm::ext::translate(&Mat4::one(), target - eye);
+    let far = dist + radius;
+    let near = (dist - radius).max(far * 1e-3);
+    (model_view, reversed_z(perspective(fov, 1., near, far)))
+}
+
+// pos指向光源的单位向量和到光源的距离
+fn to_light(position: Vec3, pos: Vec3) -> (Vec3, f32) {
+    let v = position - pos;
+    let d = glm::length(v);
+    if d > f32::EPSILON {
+        (v / d, d)
+    } else {
+        (glm::vec3(0., 0., 0.), 0.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v4p2v3;
+
+    #[test]
+    fn attenuation_factor() {
+        let default = Attenuation::default();
+        assert_eq!(default.factor(0.), 1.);
+        assert_eq!(default.factor(2.), 0.2);
+        assert_eq!(Attenuation::NONE.factor(100.), 1.);
+        let a = Attenuation {
+            constant: 1.,
+            linear: 0.5,
+            quadratic: 0.25,
+        };
+        assert!((a.factor(2.) - 1. / 3.).abs() < 1e-6);
+        // 分母为0时不衰减，而不是得到无穷大
+        let zero = Attenuation {
+            constant: 0.,
+            linear: 0.,
+            quadratic: 0.,
+        };
+        assert_eq!(zero.factor(1.), 1.);
+    }
+
+    #[test]
+    fn spot_cone() {
+        let mut spot = Light::spot(
+            glm::vec3(0., 0., 0.),
+            glm::vec3(0., 0., -1.),
+            20f32.to_radians(),
+            30f32.to_radians(),
+        );
+        if let Light::Spot { attenuation, .. } = &mut spot {
+            *attenuation = Attenuation::NONE;
+        }
+        // 和照射方向夹角为deg、距离为1的点
+        let radiance = |deg: f32| {
+            let a = deg.to_radians();
+            spot.illuminate(glm::vec3(a.sin(), 0., -a.cos())).1.x
+        };
+        assert_eq!(radiance(0.), 1.);
+        assert_eq!(radiance(19.), 1.);
+        assert_eq!(radiance(31.), 0.);
+        assert_eq!(radiance(90.), 0.);
+        let mid = radiance(25.);
+        assert!(mid > 0. && mid < 1., "{}", mid);
+        assert!(radiance(22.) > mid && mid > radiance(28.));
+    }
+
+    #[test]
+    fn shadow_view_encloses_bounding_sphere() {
+        let (center, radius) = (glm::vec3(0.5, -1., 2.), 1.5);
+        let lights = [
+            Light::directional(glm::vec3(1., 2., 3.)),
+            Light::directional(glm::vec3(0., 1., 0.)),
+            Light::point(center + glm::vec3(3., 4., 0.) * 2.),
+            Light::spot(
+                center + glm::vec3(0., 0., 5.),
+                glm::vec3(0., 0., -1.),
+                20f32.to_radians(),
+                30f32.to_radians(),
+            ),
+        ];
+        // 球面上均匀分布的点，包括和视线相切的轮廓
+        let points = (0..200).map(|i| {
+            let z = 1. - 2. * (i as f32 + 0.5) / 200.;
+            let phi = i as f32 * 2.399_963;
+            let r = (1. - z * z).sqrt();
+            center + glm::vec3(r * phi.cos(), r * phi.sin(), z) * radius
+        });
+        for light in lights {
+            let (model_view, projection) = light.shadow_view(center, radius);
+            for p in points.clone() {
+                let ndc = v4p2v3(projection * model_view * p.extend(1.));
+                let inside = [ndc.x, ndc.y, ndc.z].iter().all(|c| c.abs() <= 1. + 1e-4);
+                assert!(inside, "{:?}: {:?} -> {:?}", light, p, ndc);
+            }
+        }
+    }
+}
@@ -1,33 +1,32 @@
-use std::mem::swap;
+use std::{mem::swap, str::FromStr};
 
+use clip::ClipVertex;
+use depth::DepthBuffer;
 use glm::Vec3;
-use image::{GenericImage, Luma, Rgba};
-use our_gl::IShader;
+use image::{GenericImage, Rgba};
+use msaa::SampleCount;
+use num::Float;
+use our_gl::{Fragment, IShader};
+use serde::Deserialize;
+use target::RenderTarget;
 
-use crate::v4p2v3;
+use crate::{error::RenderError, parse_name, v4p2v3};
 
+pub mod ao_bake;
+pub mod clip;
+pub mod deferred;
+pub mod depth;
+pub mod edge;
+pub mod light;
+pub mod msaa;
 pub mod our_gl;
-
-// 求重心坐标
-fn barycentric(a: glm::Vec3, b: glm::Vec3, c: glm::Vec3, p: glm::Vec3) -> glm::Vec3 {
-    let ab = b - a;
-    let ac = c - a;
-    let pa = a - p;
-
-    let u = glm::cross(
-        glm::vec3(ab.x as f32, ac.x as f32, pa.x as f32),
-        glm::vec3(ab.y as f32, ac.y as f32, pa.y as f32),
-    );
-
-    // z是0，这种情况是因为三角形三个顶点在一条直线上，不是合法三角形
-    // 这种情况返回一个负值
-    if u.z.abs() <= f32::EPSILON {
-        return glm::vec3(-1., 1., 1.);
-    }
-
-    // vec(x,y,z)/z -> (u,v,1) -> (1-u-v, u, v)
-    return glm::vec3(1. - ((u.x + u.y) / u.z) as f32, u.x / u.z, u.y / u.z);
-}
+pub mod shadow;
+pub mod shadow_filter;
+pub mod ssaa;
+pub mod ssao;
+pub mod target;
+pub mod texture;
+pub mod tile;
 
 pub fn triangle<I: GenericImage>(
     t0: glm::Vec3,
@@ -37,33 +36,19 @@ pub fn triangle<I: GenericImage>(
     color: I::Pixel,
     zbuffer: &mut [f32],
 ) {
-    let bboxmin = glm::vec2(
-        t0.x.min(t1.x).min(t2.x).max(0.),
-        t0.y.min(t1.y).min(t2.y).max(0.),
-    );
-    let bboxmax = glm::vec2(
-        t0.x.max(t1.x).max(t2.x).min(image.width() as f32 - 1.),
-        t0.y.max(t1.y).max(t2.y).min(image.height() as f32 - 1.),
-    );
-
-    for px in bboxmin.x as i32..=bboxmax.x as i32 {
-        for py in bboxmin.y as i32..=bboxmax.y as i32 {
-            let bc_screen = barycentric(t0, t1, t2, glm::vec3(px as f32, py as f32, 0.));
-
-            if bc_screen.x < 0. || bc_screen.y < 0. || bc_screen.z < 0. {
-                continue;
-            }
-            // 计算z值
-            let pz = glm::dot(glm::vec3(t0.z, t1.z, t2.z), bc_screen);
-            let idx = px + py * image.width() as i32;
-            if zbuffer[idx as usize] <= pz {
-                zbuffer[idx as usize] = pz;
-                image.put_pixel(px as u32, py as u32, color);
-            }
+    let (w, h) = (image.width() as i32, image.height() as i32);
+    edge::for_each_pixel([t0, t1, t2], (0, 0), (w - 1, h - 1), |px, py, bc_screen| {
+        // 计算z值
+        let pz = glm::dot(glm::vec3(t0.z, t1.z, t2.z), bc_screen);
+        let idx = (px + py * w) as usize;
+        if zbuffer[idx] <= pz {
+            zbuffer[idx] = pz;
+            image.put_pixel(px as u32, py as u32, color);
         }
-    }
+    });
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn triangle_with_texture<I: GenericImage<Pixel = Rgba<u8>>>(
     a: glm::Vec3,
     b: glm::Vec3,
@@ -76,90 +61,284 @@ pub fn triangle_with_texture<I: GenericImage<Pixel = Rgba<u8>>>(
     zbuffer: &mut [f32],
     diffuse: &I,
 ) {
-    let bboxmin = glm::vec2(a.x.min(b.x).min(c.x).max(0.), a.y.min(b.y).min(c.y).max(0.));
-    let bboxmax = glm::vec2(
-        a.x.max(b.x).max(c.x).min(image.width() as f32 - 1.),
-        a.y.max(b.y).max(c.y).min(image.height() as f32 - 1.),
-    );
+    let (w, h) = (image.width() as i32, image.height() as i32);
+    edge::for_each_pixel([a, b, c], (0, 0), (w - 1, h - 1), |px, py, bc_screen| {
+        // 计算z值
+        let pz = glm::dot(glm::vec3(a.z, b.z, c.z), bc_screen);
+        // 计算纹理插值
+        let tx = glm::dot(glm::vec3(ta.x, tb.x, tc.x), bc_screen) * diffuse.width() as f32;
+        let ty = glm::dot(glm::vec3(ta.y, tb.y, tc.y), bc_screen) * diffuse.height() as f32;
+        let idx = (px + py * w) as usize;
+        let pi: Rgba<u8> = diffuse.get_pixel(tx as u32, ty as u32);
+        if zbuffer[idx] <= pz {
+            zbuffer[idx] = pz;
+            image.put_pixel(
+                px as u32,
+                py as u32,
+                Rgba([
+                    (pi.0[0] as f32 * intensity) as u8,
+                    (pi.0[1] as f32 * intensity) as u8,
+                    (pi.0[2] as f32 * intensity) as u8,
+                    255,
+                ]),
+            );
+        }
+    });
+}
 
-    for px in bboxmin.x as i32..=bboxmax.x as i32 {
-        for py in bboxmin.y as i32..=bboxmax.y as i32 {
-            let bc_screen = barycentric(a, b, c, glm::vec3(px as f32, py as f32, 0.));
+/// varying的插值方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    /// 直接用屏幕空间的重心坐标插值，透视投影下纹理会扭曲，保留用于对比
+    Affine,
+    /// 透视矫正插值，重心坐标先除以各顶点的w再归一化
+    #[default]
+    Perspective,
+}
 
-            if bc_screen.x < 0. || bc_screen.y < 0. || bc_screen.z < 0. {
-                continue;
-            }
-            // 计算z值
-            let pz = glm::dot(glm::vec3(a.z, b.z, c.z), bc_screen);
-            // 计算纹理插值
-            let tx = glm::dot(glm::vec3(ta.x, tb.x, tc.x), bc_screen) * diffuse.width() as f32;
-            let ty = glm::dot(glm::vec3(ta.y, tb.y, tc.y), bc_screen) * diffuse.height() as f32;
-            let idx = px + py * image.width() as i32;
-            let pi: Rgba<u8> = diffuse.get_pixel(tx as u32, ty as u32);
-            if zbuffer[idx as usize] <= pz {
-                zbuffer[idx as usize] = pz;
-                image.put_pixel(
-                    px as u32,
-                    py as u32,
-                    Rgba([
-                        (pi.0[0] as f32 * intensity) as u8,
-                        (pi.0[1] as f32 * intensity) as u8,
-                        (pi.0[2] as f32 * intensity) as u8,
-                        255,
-                    ]),
-                );
-            }
+/// 剔除哪一面的三角形
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CullMode {
+    #[default]
+    None,
+    Front,
+    Back,
+}
+
+impl FromStr for CullMode {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+/// 正面三角形在屏幕上的环绕方向，屏幕坐标y向上
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrontFace {
+    Cw,
+    #[default]
+    Ccw,
+}
+
+impl FromStr for FrontFace {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+/// 光栅化状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RasterizerState {
+    pub cull_mode: CullMode,
+    pub front_face: FrontFace,
+    pub interpolation: Interpolation,
+}
+
+impl RasterizerState {
+    /// 按屏幕空间的有向面积(逆时针为正)判断是否剔除，面积为0的三角形总是被剔除
+    pub fn culls(&self, signed_area: i64) -> bool {
+        if signed_area == 0 {
+            return true;
+        }
+        let front = (signed_area > 0) == (self.front_face == FrontFace::Ccw);
+        match self.cull_mode {
+            CullMode::None => false,
+            CullMode::Front => front,
+            CullMode::Back => !front,
         }
     }
 }
 
-/// 注意现在输入的顶点坐标是齐次坐标
-pub fn triangle_with_shader<
-    I: GenericImage<Pixel = Rgba<u8>>,
-    I2: GenericImage<Pixel = Luma<u8>>,
+/// 输入是顶点着色器的输出: 裁剪空间的齐次坐标和顶点的varying
+///
+/// 三角形先在齐次空间中用视锥体裁剪，再做透视除法和视口变换后光栅化
+///
+/// 片段着色器的输出写入target，samples大于1时target和zbuffer按采样点存储，布局见 msaa::MsaaBuffer
+pub fn triangle_with_shader<T, D, S>(
+    vertices: [(glm::Vec4, S::Varyings); 3],
+    view_port: &glm::Mat4,
+    state: &RasterizerState,
+    samples: SampleCount,
+    shader: &S,
+    target: &mut T,
+    zbuffer: &mut DepthBuffer<D>,
+) where
+    T: RenderTarget<Value = S::Output>,
+    D: Float + From<f32>,
     S: IShader,
->(
-    a_4d: glm::Vec4,
-    b_4d: glm::Vec4,
-    c_4d: glm::Vec4,
-    shader: &mut S,
-    image: &mut I,
-    zbuffer: &mut I2,
-) {
-    let a = v4p2v3(a_4d);
-    let b = v4p2v3(b_4d);
-    let c = v4p2v3(c_4d);
-    let bboxmin = glm::vec2(a.x.min(b.x).min(c.x).max(0.), a.y.min(b.y).min(c.y).max(0.));
-    let bboxmax = glm::vec2(
-        a.x.max(b.x).max(c.x).min(image.width() as f32 - 1.),
-        a.y.max(b.y).max(c.y).min(image.height() as f32 - 1.),
+{
+    let region = Region {
+        x: 0,
+        y: 0,
+        w: (target.width() / samples.count()) as i32,
+        h: target.height() as i32,
+    };
+    let polygon = clip::clip_triangle(vertices.map(|v| v.0));
+    rasterize_polygon(
+        &polygon,
+        &vertices.map(|v| v.1),
+        view_port,
+        state,
+        samples,
+        region,
+        shader,
+        target,
+        zbuffer,
     );
+}
+
+/// 光栅化的屏幕区域(像素)，target和zbuffer的(0,0)对应屏幕上的(x,y)
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+// 裁剪结果是凸多边形，按扇形拆成三角形，varyings是原三角形三个顶点的varying
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn rasterize_polygon<T, D, S>(
+    polygon: &[ClipVertex],
+    varyings: &[S::Varyings; 3],
+    view_port: &glm::Mat4,
+    state: &RasterizerState,
+    samples: SampleCount,
+    region: Region,
+    shader: &S,
+    target: &mut T,
+    zbuffer: &mut DepthBuffer<D>,
+) where
+    T: RenderTarget<Value = S::Output>,
+    D: Float + From<f32>,
+    S: IShader,
+{
+    for i in 1..polygon.len().saturating_sub(1) {
+        rasterize_clipped(
+            [polygon[0], polygon[i], polygon[i + 1]],
+            varyings,
+            view_port,
+            state,
+            samples,
+            region,
+            shader,
+            target,
+            zbuffer,
+        );
+    }
+}
 
-    for px in bboxmin.x as i32..=bboxmax.x as i32 {
-        for py in bboxmin.y as i32..=bboxmax.y as i32 {
-            let bc_screen = barycentric(a, b, c, glm::vec3(px as f32, py as f32, 0.));
-            // 留意下这里，z和w都使用齐次坐标算的
-            let z = glm::dot(glm::vec3(a_4d.z, b_4d.z, c_4d.z), bc_screen);
-            let w = glm::dot(glm::vec3(a_4d.w, b_4d.w, c_4d.w), bc_screen);
+#[allow(clippy::too_many_arguments)]
+fn rasterize_clipped<T, D, S>(
+    tri: [ClipVertex; 3],
+    varyings: &[S::Varyings; 3],
+    view_port: &glm::Mat4,
+    state: &RasterizerState,
+    samples: SampleCount,
+    region: Region,
+    shader: &S,
+    target: &mut T,
+    zbuffer: &mut DepthBuffer<D>,
+) where
+    T: RenderTarget<Value = S::Output>,
+    D: Float + From<f32>,
+    S: IShader,
+{
+    // 屏幕坐标
+    let a = v4p2v3(*view_port * tri[0].pos);
+    let b = v4p2v3(*view_port * tri[1].pos);
+    let c = v4p2v3(*view_port * tri[2].pos);
+    if state.culls(edge::signed_area([a, b, c])) {
+        return;
+    }
+    // 每一列是一个裁剪顶点相对原三角形的重心坐标
+    #[rustfmt::skip]
+    let bar_tri = glm::mat3(
+        tri[0].bar.x, tri[0].bar.y, tri[0].bar.z,
+        tri[1].bar.x, tri[1].bar.y, tri[1].bar.z,
+        tri[2].bar.x, tri[2].bar.y, tri[2].bar.z,
+    );
+    // 三个顶点w的倒数，用于透视矫正
+    let w_inv = glm::vec3(1. / tri[0].pos.w, 1. / tri[1].pos.w, 1. / tri[2].pos.w);
+    let interpolate = |bc_screen: Vec3| match state.interpolation {
+        Interpolation::Affine => bc_screen,
+        Interpolation::Perspective => {
+            // 屏幕空间线性的是 attr/w 和 1/w，两者相除得到裁剪空间的重心坐标
+            let bc_clip = bc_screen * w_inv;
+            bc_clip / (bc_clip.x + bc_clip.y + bc_clip.z)
+        }
+    };
+    // 重心坐标的导数用相邻像素的差分计算，和GPU上2x2像素块的做法一样
+    let (grad_x, grad_y) = edge::barycentric_gradient([a, b, c]);
+    let fragment = |px: i32, py: i32, bc_screen: Vec3| {
+        let bar = bar_tri * interpolate(bc_screen);
+        let dx = bar_tri * interpolate(bc_screen + grad_x) - bar;
+        let dy = bar_tri * interpolate(bc_screen + grad_y) - bar;
+        let frag_coord = glm::vec2(px as f32 + 0.5, py as f32 + 0.5);
+        Fragment::new(varyings, bar, dx, dy, frag_coord)
+    };
+    // 透视除法后的z在屏幕空间是线性的，可以直接插值
+    let depth = |bc_screen: Vec3| glm::dot(glm::vec3(a.z, b.z, c.z), bc_screen);
+    // 只绘制区域内的像素，整张图和逐块绘制时每个像素的计算完全相同
+    let min = (region.x, region.y);
+    let max = (region.x + region.w - 1, region.y + region.h - 1);
 
-            let frag_depth = (z / w + 0.5) as u8;
-            let frag_depth = frag_depth.min(255).max(0);
+    if samples == SampleCount::X1 {
+        edge::for_each_pixel([a, b, c], min, max, |px, py, bc_screen| {
+            let z = depth(bc_screen).into();
+            let (x, y) = ((px - region.x) as u32, (py - region.y) as u32);
+            // 先做深度测试，没通过就不运行片段着色器
+            if !zbuffer.test(x, y, z) {
+                return;
+            }
+            // 片段被丢弃时和GLSL的discard一样，不写颜色也不写深度
+            if let Some(output) = shader.fragment(&fragment(px, py, bc_screen)) {
+                zbuffer.write(x, y, z);
+                target.put(x, y, output);
+            }
+        });
+        return;
+    }
 
-            if bc_screen.x < 0. || bc_screen.y < 0. || bc_screen.z < 0. {
-                continue;
+    let n = samples.count();
+    let pattern = samples.pattern();
+    edge::for_each_pixel_multisample([a, b, c], min, max, pattern, |px, py, mask, center, bcs| {
+        // 像素中心在三角形外时在第一个被覆盖的采样点着色，避免varying外插
+        let inside = center.x >= 0. && center.y >= 0. && center.z >= 0.;
+        let bc_screen = if inside {
+            center
+        } else {
+            bcs[mask.trailing_zeros() as usize]
+        };
+        let (x, y) = ((px - region.x) as u32 * n, (py - region.y) as u32);
+        // 覆盖并且通过深度测试的采样点，一个都没有时不运行片段着色器
+        let passed = (0..n).fold(0, |passed, s| {
+            let covered = mask & (1 << s) != 0;
+            if covered && zbuffer.test(x + s, y, depth(bcs[s as usize]).into()) {
+                passed | 1 << s
+            } else {
+                passed
             }
-            let mut color = image::Rgba([0; 4]);
-            let discard = shader.fragment(bc_screen, &mut color);
-            let idx = px + py * image.width() as i32;
-            let zb: &mut Luma<u8> = zbuffer.get_pixel_mut(px as _, py as _);
-            if zb.0[0] <= frag_depth {
-                zb.0[0] = frag_depth;
-                if !discard {
-                    image.put_pixel(px as u32, py as u32, color);
-                }
+        });
+        if passed == 0 {
+            return;
+        }
+        let Some(output) = shader.fragment(&fragment(px, py, bc_screen)) else {
+            return;
+        };
+        for (s, &bc) in bcs.iter().enumerate() {
+            if passed & (1 << s) != 0 {
+                let x = x + s as u32;
+                zbuffer.write(x, y, depth(bc).into());
+                target.put(x, y, output);
             }
         }
-    }
+    });
 }
 
 pub fn line<I: GenericImage>(mut a: glm::IVec2, mut b: glm::IVec2, image: &mut I, color: I::Pixel) {
@@ -241,13 +420,228 @@ pub fn lookat(eye: glm::Vec3, center: glm::Vec3, up: Vec3) -> glm::Matrix4<f32>
 
 pub fn viewport(x: i32, y: i32, w: i32, h: i32) -> glm::Matrix4<f32> {
     let (x, y, w, h) = (x as f32, y as f32, w as f32, h as f32);
-    let d = 255.;
+    let d = 1.; // 深度映射到[0,1]，配合浮点深度缓冲
     #[rustfmt::skip]
     let m = glm::mat4(
-        w/2., 0., 0., 0., 
-        0., h/2., 0., 0., 
-        0., 0., d/2., 0., 
+        w/2., 0., 0., 0.,
+        0., h/2., 0., 0.,
+        0., 0., d/2., 0.,
         x+w/2., y+h/2., d/2., 1.,
     );
     m
 }
+
+// 下面的投影矩阵和OpenGL一致: 摄像机看向-z，可见范围映射到NDC [-1,1]^3
+// viewport再把NDC的z映射到[0,1]作为深度
+// 普通投影近处深度为0、远处为1，需要 DepthFunc::Less 并清空为1
+// reversed_z 后近处为1、远处为0，和 Renderer 默认的 GreaterEqual 清空为0一致
+
+/// 透视投影，视锥体由近平面上的矩形[left,right]x[bottom,top]确定
+///
+/// near和far是到摄像机的距离，都要大于0
+pub fn frustum(
+    left: f32,
+    right: f32,
+    bottom: f32,
+    top: f32,
+    near: f32,
+    far: f32,
+) -> glm::Matrix4<f32> {
+    let (w, h, d) = (right - left, top - bottom, far - near);
+    #[rustfmt::skip]
+    let m = glm::mat4(
+        2.*near/w, 0., 0., 0.,
+        0., 2.*near/h, 0., 0.,
+        (right+left)/w, (top+bottom)/h, -(far+near)/d, -1.,
+        0., 0., -2.*far*near/d, 0.,
+    );
+    m
+}
+
+/// 对称的透视投影，fov_y是垂直方向的视角(弧度)，aspect是宽/高
+pub fn perspective(fov_y: f32, aspect: f32, near: f32, far: f32) -> glm::Matrix4<f32> {
+    let top = near * (fov_y / 2.).tan();
+    let right = top * aspect;
+    frustum(-right, right, -top, top, near, far)
+}
+
+/// 远平面在无穷远处的透视投影，不会因为far裁掉远处的物体
+pub fn infinite_perspective(fov_y: f32, aspect: f32, near: f32) -> glm::Matrix4<f32> {
+    let f = 1. / (fov_y / 2.).tan();
+    #[rustfmt::skip]
+    let m = glm::mat4(
+        f/aspect, 0., 0., 0.,
+        0., f, 0., 0.,
+        0., 0., -1., -1.,
+        0., 0., -2.*near, 0.,
+    );
+    m
+}
+
+/// 正交投影，把[left,right]x[bottom,top]x[-near,-far]映射到NDC
+pub fn orthographic(
+    left: f32,
+    right: f32,
+    bottom: f32,
+    top: f32,
+    near: f32,
+    far: f32,
+) -> glm::Matrix4<f32> {
+    let (w, h, d) = (right - left, top - bottom, far - near);
+    #[rustfmt::skip]
+    let m = glm::mat4(
+        2./w, 0., 0., 0.,
+        0., 2./h, 0., 0.,
+        0., 0., -2./d, 0.,
+        -(right+left)/w, -(top+bottom)/h, -(far+near)/d, 1.,
+    );
+    m
+}
+
+/// 翻转投影的NDC z，近平面映射到1，远平面映射到-1
+///
+/// 经过viewport后深度越大越近，配合 DepthFunc::Greater/GreaterEqual 使用
+pub fn reversed_z(projection: glm::Matrix4<f32>) -> glm::Matrix4<f32> {
+    #[rustfmt::skip]
+    let flip = glm::mat4(
+        1., 0., 0., 0.,
+        0., 1., 0., 0.,
+        0., 0., -1., 0.,
+        0., 0., 0., 1.,
+    );
+    flip * projection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ndc(m: glm::Matrix4<f32>, x: f32, y: f32, z: f32) -> Vec3 {
+        v4p2v3(m * glm::vec4(x, y, z, 1.))
+    }
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        let d = actual - expected;
+        assert!(
+            d.x.abs() < 1e-5 && d.y.abs() < 1e-5 && d.z.abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn cull_mode_against_front_face() {
+        // (环绕方向, 剔除方式, 逆时针三角形是否剔除, 顺时针三角形是否剔除)
+        let cases = [
+            (FrontFace::Ccw, CullMode::None, false, false),
+            (FrontFace::Ccw, CullMode::Back, false, true),
+            (FrontFace::Ccw, CullMode::Front, true, false),
+            (FrontFace::Cw, CullMode::None, false, false),
+            (FrontFace::Cw, CullMode::Back, true, false),
+            (FrontFace::Cw, CullMode::Front, false, true),
+        ];
+        for (front_face, cull_mode, ccw, cw) in cases {
+            let state = RasterizerState {
+                cull_mode,
+                front_face,
+                ..Default::default()
+            };
+            assert_eq!(state.culls(2), ccw, "{:?} {:?} ccw", front_face, cull_mode);
+            assert_eq!(state.culls(-2), cw, "{:?} {:?} cw", front_face, cull_mode);
+            assert!(
+                state.culls(0),
+                "{:?} {:?} degenerate",
+                front_face,
+                cull_mode
+            );
+        }
+    }
+
+    #[test]
+    fn perspective_maps_frustum_corners() {
+        let m = perspective(90f32.to_radians(), 2., 1., 10.);
+        assert_near(ndc(m, 0., 0., -1.), glm::vec3(0., 0., -1.));
+        assert_near(ndc(m, 0., 0., -10.), glm::vec3(0., 0., 1.));
+        assert_near(ndc(m, 2., 1., -1.), glm::vec3(1., 1., -1.));
+        assert_near(ndc(m, -20., -10., -10.), glm::vec3(-1., -1., 1.));
+    }
+
+    #[test]
+    fn frustum_off_center() {
+        let m = frustum(0., 2., 0., 1., 1., 3.);
+        assert_near(ndc(m, 0., 0., -1.), glm::vec3(-1., -1., -1.));
+        assert_near(ndc(m, 2., 1., -1.), glm::vec3(1., 1., -1.));
+        assert_near(ndc(m, 6., 3., -3.), glm::vec3(1., 1., 1.));
+    }
+
+    #[test]
+    fn infinite_perspective_never_reaches_far() {
+        let m = infinite_perspective(90f32.to_radians(), 1., 0.5);
+        assert_near(ndc(m, 0.5, 0.5, -0.5), glm::vec3(1., 1., -1.));
+        let far = ndc(m, 0., 0., -1e4);
+        assert!(far.z < 1. && far.z > 0.99);
+    }
+
+    #[test]
+    fn orthographic_maps_box() {
+        let m = orthographic(-2., 2., -1., 1., 1., 5.);
+        assert_near(ndc(m, 2., 1., -1.), glm::vec3(1., 1., -1.));
+        assert_near(ndc(m, -2., -1., -5.), glm::vec3(-1., -1., 1.));
+        assert_near(ndc(m, 0., 0., -3.), glm::vec3(0., 0., 0.));
+    }
+
+    #[test]
+    fn reversed_z_flips_depth() {
+        let m = reversed_z(perspective(60f32.to_radians(), 1., 1., 100.));
+        assert_near(ndc(m, 0., 0., -1.), glm::vec3(0., 0., 1.));
+        assert_near(ndc(m, 0., 0., -100.), glm::vec3(0., 0., -1.));
+
+        let m = reversed_z(infinite_perspective(60f32.to_radians(), 1., 1.));
+        assert_near(ndc(m, 0., 0., -1.), glm::vec3(0., 0., 1.));
+        assert!(ndc(m, 0., 0., -1e4).z < -0.99);
+    }
+
+    #[test]
+    fn viewport_depth_matches_projection() {
+        let vp = viewport(0, 0, 100, 100);
+        let depth = |m: glm::Matrix4<f32>, z: f32| v4p2v3(vp * m * glm::vec4(0., 0., z, 1.)).z;
+
+        // 普通投影: 近0远1
+        let m = perspective(90f32.to_radians(), 1., 1., 10.);
+        assert!((depth(m, -1.) - 0.).abs() < 1e-5);
+        assert!((depth(m, -10.) - 1.).abs() < 1e-5);
+        assert!(depth(m, -2.) < depth(m, -5.));
+
+        // reversed-z: 近1远0，和Renderer默认的深度测试一致
+        let m = reversed_z(m);
+        assert!((depth(m, -1.) - 1.).abs() < 1e-5);
+        assert!((depth(m, -10.) - 0.).abs() < 1e-5);
+        assert!(depth(m, -2.) > depth(m, -5.));
+    }
+
+    #[test]
+    fn shared_edges_cover_each_pixel_once() {
+        // 正方形和扇形拆分，边和顶点都落在像素中心上
+        let v = [
+            glm::vec3(0.5, 0.5, 0.),
+            glm::vec3(8.5, 0.5, 0.),
+            glm::vec3(8.5, 8.5, 0.),
+            glm::vec3(0.5, 8.5, 0.),
+            glm::vec3(4.5, 4.5, 0.),
+        ];
+        let mut count = [[0; 10]; 10];
+        for (a, b, c) in [(0, 1, 4), (1, 2, 4), (4, 2, 3), (0, 4, 3)] {
+            edge::for_each_pixel([v[a], v[b], v[c]], (0, 0), (9, 9), |x, y, _| {
+                count[y as usize][x as usize] += 1;
+            });
+        }
+        for (y, row) in count.iter().enumerate() {
+            for (x, &n) in row.iter().enumerate() {
+                // y向上，左边和上边属于正方形，右边和下边不属于
+                let inside = x < 8 && (1..=8).contains(&y);
+                assert_eq!(n, inside as i32, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! 多重采样抗锯齿
+//!
+//! 每个像素有多个采样点，覆盖和深度测试按采样点进行，着色器每个像素只运行一次，
+//! 结果写入所有通过测试的采样点，最后把采样点平均得到最终图像
+
+use std::str::FromStr;
+
+use image::{ImageBuffer, Rgba};
+use num::Float;
+use serde::de::{Error, Unexpected};
+
+use crate::error::RenderError;
+
+use super::depth::{DepthBuffer, DepthFunc};
+
+/// 每个像素的采样点数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleCount {
+    #[default]
+    X1,
+    X2,
+    X4,
+    X8,
+}
+
+// 采样点数写成数字，比如 "4"
+impl FromStr for SampleCount {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .ok()
+            .and_then(SampleCount::from_count)
+            .ok_or_else(|| {
+                serde::de::value::Error::invalid_value(Unexpected::Str(s), &"1, 2, 4 or 8").into()
+            })
+    }
+}
+
+impl SampleCount {
+    pub fn count(self) -> u32 {
+        match self {
+            SampleCount::X1 => 1,
+            SampleCount::X2 => 2,
+            SampleCount::X4 => 4,
+            SampleCount::X8 => 8,
+        }
+    }
+
+    /// 从采样点数构造，只支持1、2、4、8
+    pub fn from_count(count: u32) -> Option<SampleCount> {
+        match count {
+            1 => Some(SampleCount::X1),
+            2 => Some(SampleCount::X2),
+            4 => Some(SampleCount::X4),
+            8 => Some(SampleCount::X8),
+            _ => None,
+        }
+    }
+
+    /// 采样点相对像素中心的偏移，单位1/16像素
+    ///
+    /// 和D3D的标准采样模式相同，因为屏幕坐标y向上，y分量取反
+    pub fn pattern(self) -> &'static [(i32, i32)] {
+        match self {
+            SampleCount::X1 => &[(0, 0)],
+            SampleCount::X2 => &[(4, -4), (-4, 4)],
+            SampleCount::X4 => &[(-2, 6), (6, 2), (-6, -2), (2, -6)],
+            SampleCount::X8 => &[
+                (1, 3),
+                (-1, -3),
+                (5, -1),
+                (-3, 5),
+                (-5, -5),
+                (-7, 1),
+                (3, -7),
+                (7, 7),
+            ],
+        }
+    }
+}
+
+type Image = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+/// 多重采样的颜色和深度缓冲
+///
+/// 按采样点存储，宽度是像素宽度乘采样点数，同一像素的采样点在一行里相邻，
+/// 像素(x,y)的第s个采样点位于(x*n+s, y)
+pub struct MsaaBuffer<D = f32> {
+    samples: SampleCount,
+    color: Image,
+    depth: DepthBuffer<D>,
+}
+
+impl<D: Float> MsaaBuffer<D> {
+    pub fn new(
+        width: u32,
+        height: u32,
+        samples: SampleCount,
+        color: Rgba<u8>,
+        func: DepthFunc,
+        clear_depth: D,
+    ) -> Self {
+        let n = samples.count();
+        Self {
+            samples,
+            color: ImageBuffer::from_pixel(width * n, height, color),
+            depth: DepthBuffer::new(width * n, height, func, clear_depth),
+        }
+    }
+
+    pub fn samples(&self) -> SampleCount {
+        self.samples
+    }
+
+    /// 像素宽度
+    pub fn width(&self) -> u32 {
+        self.color.width() / self.samples.count()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.color.height()
+    }
+
+    pub fn color(&self) -> &Image {
+        &self.color
+    }
+
+    pub fn depth(&self) -> &DepthBuffer<D> {
+        &self.depth
+    }
+
+    pub fn depth_mut(&mut self) -> &mut DepthBuffer<D> {
+        &mut self.depth
+    }
+
+    /// 颜色和深度缓冲，用来绘制
+    pub fn buffers_mut(&mut self) -> (&mut Image, &mut DepthBuffer<D>) {
+        (&mut self.color, &mut self.depth)
+    }
+
+    pub fn clear(&mut self, color: Rgba<u8>) {
+        self.color.pixels_mut().for_each(|p| *p = color);
+        self.depth.clear();
+    }
+
+    /// 每个像素的采样点取平均，得到最终的颜色
+    pub fn resolve_color(&self) -> Image {
+        let n = self.samples.count();
+        ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
+            let mut sum = [0u32; 4];
+            for s in 0..n {
+                let p = self.color.get_pixel(x * n + s, y);
+                sum.iter_mut()
+                    .zip(p.0)
+                    .for_each(|(sum, c)| *sum += c as u32);
+            }
+            // 四舍五入
+            Rgba(sum.map(|c| ((c + n / 2) / n) as u8))
+        })
+    }
+
+    /// 每个像素取深度测试意义上最近的采样点，深度测试设置保持不变
+    ///
+    /// 深度不能平均: 轮廓处会和清除值混合，得到场景中不存在的深度
+    pub fn resolve_depth(&self) -> DepthBuffer<D> {
+        let n = self.samples.count();
+        let func = self.depth.func();
+        let mut depth =
+            DepthBuffer::new(self.width(), self.height(), func, self.depth.clear_value());
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let mut nearest = self.depth.get(x * n, y);
+                for s in 1..n {
+                    let d = self.depth.get(x * n + s, y);
+                    if func.compare(d, nearest) {
+                        nearest = d;
+                    }
+                }
+                depth.set(x, y, nearest);
+            }
+        }
+        depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_resolve_keeps_nearest_sample() {
+        let black = Rgba([0, 0, 0, 255]);
+        for (func, clear, near, far) in [
+            (DepthFunc::GreaterEqual, 0., 0.8, 0.3),
+            (DepthFunc::Less, 1., 0.2, 0.6),
+        ] {
+            let mut msaa = MsaaBuffer::new(2, 1, SampleCount::X4, black, func, clear);
+            // 第一个像素只有一半采样点被覆盖，第二个像素的采样点深度不同
+            let depth = msaa.depth_mut();
+            depth.set(0, 0, near);
+            depth.set(1, 0, near);
+            for (s, d) in [near, far, far, far].into_iter().enumerate() {
+                depth.set(4 + s as u32, 0, d);
+            }
+            let resolved = msaa.resolve_depth();
+            assert_eq!((resolved.width(), resolved.height()), (2, 1));
+            assert_eq!(resolved.as_slice(), [near, near], "{:?}", func);
+            assert_eq!(resolved.func(), func);
+        }
+    }
+}
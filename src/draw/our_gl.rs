@@ -1,20 +1,160 @@
-use glm::Vec3;
+use glm::{Vec2, Vec3, Vec4};
 
+use crate::vec4_to_3;
+
+pub mod shader_impl_gbuffer_shader;
 pub mod shader_impl_gouraud_shader;
+pub mod shader_impl_pbr_shader;
 pub mod shader_impl_phong_shader;
 pub mod shader_impl_shadow_shader;
 
+/// 可以按重心坐标插值的varying
+///
+/// 插值必须是三个值的线性组合，光栅化时用同样的方式组合重心坐标的导数得到varying的导数
+pub trait Interpolate: Copy {
+    /// 三个顶点的值按重心坐标bar组合
+    fn interpolate(v: &[Self; 3], bar: Vec3) -> Self;
+}
+
+impl Interpolate for () {
+    fn interpolate(_: &[Self; 3], _: Vec3) -> Self {}
+}
+
+impl Interpolate for f32 {
+    fn interpolate(v: &[Self; 3], bar: Vec3) -> Self {
+        v[0] * bar.x + v[1] * bar.y + v[2] * bar.z
+    }
+}
+
+macro_rules! impl_interpolate_vec {
+    ($($t:ty),*) => {
+        $(
+            impl Interpolate for $t {
+                fn interpolate(v: &[Self; 3], bar: Vec3) -> Self {
+                    v[0] * bar.x + v[1] * bar.y + v[2] * bar.z
+                }
+            }
+        )*
+    };
+}
+
+impl_interpolate_vec!(Vec2, Vec3, Vec4);
+
+impl<T: Interpolate, const N: usize> Interpolate for [T; N] {
+    fn interpolate(v: &[Self; 3], bar: Vec3) -> Self {
+        std::array::from_fn(|i| T::interpolate(&v.map(|v| v[i]), bar))
+    }
+}
+
+macro_rules! impl_interpolate_tuple {
+    ($(($($t:ident $i:tt),+)),*) => {
+        $(
+            impl<$($t: Interpolate),+> Interpolate for ($($t,)+) {
+                fn interpolate(v: &[Self; 3], bar: Vec3) -> Self {
+                    ($($t::interpolate(&v.map(|v| v.$i), bar),)+)
+                }
+            }
+        )*
+    };
+}
+
+impl_interpolate_tuple!((A 0, B 1), (A 0, B 1, C 2), (A 0, B 1, C 2, D 3));
+
+/// 使用纹理和法线贴图的着色器共用的varying
+///
+/// normal和tangent所在的空间由着色器决定，只要两者一致即可
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceVaryings {
+    pub uv: Vec2,      // 纹理坐标
+    pub pos: Vec3,     // 世界坐标
+    pub normal: Vec3,  // 法线，切线空间时和tangent一起构造TBN
+    pub tangent: Vec4, // 切线，w是副切线的方向，只在切线空间时使用
+}
+
+impl Interpolate for SurfaceVaryings {
+    fn interpolate(v: &[Self; 3], bar: Vec3) -> Self {
+        Self {
+            uv: Vec2::interpolate(&v.map(|v| v.uv), bar),
+            pos: Vec3::interpolate(&v.map(|v| v.pos), bar),
+            normal: Vec3::interpolate(&v.map(|v| v.normal), bar),
+            tangent: Vec4::interpolate(&v.map(|v| v.tangent), bar),
+        }
+    }
+}
+
+/// 把切线空间法线贴图的值nm([-1,1])变换到normal和tangent所在的空间，返回单位向量
+///
+/// 用插值后的法线和切线构造TBN，副切线按MikkTSpace的约定在像素处计算
+pub fn tangent_space_normal(normal: Vec3, tangent: Vec4, nm: Vec3) -> Vec3 {
+    let normal = glm::normalize(normal);
+    let t = vec4_to_3(tangent);
+    let t = glm::normalize(t - normal * glm::dot(normal, t));
+    let sign = if tangent.w < 0. { -1. } else { 1. };
+    let bitangent = glm::cross(normal, t) * sign;
+    glm::normalize(t * nm.x + bitangent * nm.y + normal * nm.z)
+}
+
+/// 片段着色器的输入
+#[derive(Debug, Clone, Copy)]
+pub struct Fragment<'a, V> {
+    /// 透视矫正插值后的varying
+    pub varyings: V,
+    /// 重心坐标
+    pub bar: Vec3,
+    /// 向右移动一个像素时重心坐标的变化
+    pub dx: Vec3,
+    /// 向上移动一个像素时重心坐标的变化
+    pub dy: Vec3,
+    /// 像素中心的屏幕坐标，类似GLSL的gl_FragCoord.xy
+    pub frag_coord: Vec2,
+    vertices: &'a [V; 3],
+}
+
+impl<'a, V: Interpolate> Fragment<'a, V> {
+    /// vertices是三个顶点的varying，其余参数的含义见字段说明
+    pub fn new(vertices: &'a [V; 3], bar: Vec3, dx: Vec3, dy: Vec3, frag_coord: Vec2) -> Self {
+        Self {
+            varyings: V::interpolate(vertices, bar),
+            bar,
+            dx,
+            dy,
+            frag_coord,
+            vertices,
+        }
+    }
+
+    /// varying向右移动一个像素时的变化，类似GLSL的dFdx
+    pub fn dfdx(&self) -> V {
+        V::interpolate(self.vertices, self.dx)
+    }
+
+    /// varying向上移动一个像素时的变化，类似GLSL的dFdy
+    pub fn dfdy(&self) -> V {
+        V::interpolate(self.vertices, self.dy)
+    }
+}
+
+/// 着色器
+///
+/// 顶点着色器的输出通过Varyings传给片段着色器，着色器本身不保存逐顶点的状态，
+/// 可以在多个线程间共享，也可以缓存顶点着色器的结果
 pub trait IShader {
+    /// 顶点着色器输出、由光栅化插值后传给片段着色器的数据
+    type Varyings: Interpolate;
+    /// 片段着色器的输出，写入颜色图像时是 Rgba<u8>，
+    /// 也可以是包含多个输出的结构，比如写入G-buffer的 deferred::GBufferTexel
+    type Output: Copy;
+
     /// 顶点着色器
     ///
-    /// iface 第i个面, nth_vert 面的第n个顶点
+    /// i_vert 顶点在顶点缓冲中的索引
     ///
-    /// 返回顶点在裁剪空间的坐标(齐次坐标)
-    fn vertex(&mut self, i_face: usize, nth_vert: usize) -> glm::Vec4;
+    /// 返回顶点在裁剪空间的坐标(齐次坐标)和这个顶点的varying
+    fn vertex(&self, i_vert: usize) -> (glm::Vec4, Self::Varyings);
     /// 片段着色器
     ///
-    /// bar 当前像素在三角形中的重心坐标 color 像素颜色
+    /// frag 插值后的varying和它的导数
     ///
-    /// 返回true表示丢弃当前像素
-    fn fragment(&mut self, bar: Vec3, color: &mut image::Rgba<u8>) -> bool;
+    /// 返回None表示丢弃当前像素
+    fn fragment(&self, frag: &Fragment<Self::Varyings>) -> Option<Self::Output>;
 }
@@ -0,0 +1,121 @@
+use glm::{GenMat, GenSquareMat, Mat4, Vec3, Vec4};
+use num::Zero;
+use obj::TexturedVertex;
+
+use crate::{
+    draw::{
+        deferred::GBufferTexel,
+        texture::{Sampler, Texture, Texture2D},
+    },
+    error::{RenderError, Result},
+    vec4_to_3,
+};
+
+use super::{tangent_space_normal, Fragment, IShader, SurfaceVaryings};
+
+/// 把表面属性写入G-buffer，光照在 deferred::shade 中计算
+#[derive(Clone)]
+pub struct GBufferShader<'a> {
+    model: &'a obj::Obj<TexturedVertex, u32>,
+    diffuse: Texture2D<'a>,
+    diffuse_nm: Texture2D<'a>,   // 法线贴图
+    diffuse_spec: Texture2D<'a>, // 高光贴图
+    uniform_model: Mat4,         // 模型矩阵
+    uniform_model_it: Mat4,      // 模型矩阵的逆转置，变换法线
+    uniform_vp: Mat4,            // 世界坐标 -> 裁剪空间 projection*view
+    material_id: u32,
+    tangents: Option<&'a [glm::Vec4]>, // 每个顶点的切线，Some时法线贴图在切线空间
+}
+
+impl<'a> GBufferShader<'a> {
+    /// 模型矩阵不可逆时无法变换法线，返回错误
+    pub fn new(
+        model: &'a obj::Obj<TexturedVertex, u32>,
+        diffuse: &'a Texture,
+        diffuse_nm: &'a Texture,
+        diffuse_spec: &'a Texture,
+        uniform_model: Mat4,
+        uniform_vp: Mat4,
+    ) -> Result<Self> {
+        let uniform_model_it = uniform_model
+            .inverse()
+            .ok_or(RenderError::SingularMatrix("model"))?
+            .transpose();
+        Ok(Self {
+            model,
+            diffuse: Texture2D::new(diffuse, Sampler::default()),
+            diffuse_nm: Texture2D::new(diffuse_nm, Sampler::default()),
+            diffuse_spec: Texture2D::new(diffuse_spec, Sampler::default()),
+            uniform_model,
+            uniform_model_it,
+            uniform_vp,
+            material_id: 1,
+            tangents: None,
+        })
+    }
+
+    /// 写入G-buffer的材质编号，默认1，0保留给没有几何体的像素
+    pub fn with_material_id(mut self, material_id: u32) -> Self {
+        self.material_id = material_id.max(1);
+        self
+    }
+
+    /// 所有贴图的采样方式，默认三线性过滤、平铺
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.diffuse.set_sampler(sampler);
+        self.diffuse_nm.set_sampler(sampler);
+        self.diffuse_spec.set_sampler(sampler);
+        self
+    }
+
+    /// 法线贴图按切线空间解释，默认是物体空间，见 PhongShader::with_tangents
+    pub fn with_tangents(mut self, tangents: &'a [glm::Vec4]) -> Self {
+        self.tangents = Some(tangents);
+        self
+    }
+}
+
+impl<'a> IShader for GBufferShader<'a> {
+    type Varyings = SurfaceVaryings;
+    type Output = GBufferTexel;
+
+    fn vertex(&self, i_vert: usize) -> (glm::Vec4, SurfaceVaryings) {
+        let vert = self.model.vertices[i_vert];
+        let world = self.uniform_model * Vec3::from_array(&vert.position).extend(1.);
+        let mut varyings = SurfaceVaryings {
+            uv: glm::vec2(vert.texture[0], vert.texture[1]),
+            pos: vec4_to_3(world),
+            normal: Vec3::zero(),
+            tangent: Vec4::zero(),
+        };
+        if let Some(tangents) = self.tangents {
+            let normal = Vec3::from_array(&vert.normal);
+            let tangent = tangents[i_vert];
+            varyings.normal = vec4_to_3(self.uniform_model_it * normal.extend(0.));
+            varyings.tangent =
+                vec4_to_3(self.uniform_model * vec4_to_3(tangent).extend(0.)).extend(tangent.w);
+        }
+        (self.uniform_vp * world, varyings)
+    }
+
+    fn fragment(&self, frag: &Fragment<SurfaceVaryings>) -> Option<GBufferTexel> {
+        let v = frag.varyings;
+        let (uv, duv_dx, duv_dy) = (v.uv, frag.dfdx().uv, frag.dfdy().uv);
+        let albedo = vec4_to_3(self.diffuse.sample_grad(uv, duv_dx, duv_dy));
+        let n = vec4_to_3(self.diffuse_nm.sample_grad(uv, duv_dx, duv_dy)) * 2. - 1.;
+        let specular = self.diffuse_spec.sample_grad(uv, duv_dx, duv_dy).x * 255.;
+
+        let normal = match self.tangents {
+            Some(_) => tangent_space_normal(v.normal, v.tangent, n),
+            None => glm::normalize(vec4_to_3(self.uniform_model_it * n.extend(0.))),
+        };
+
+        Some(GBufferTexel {
+            albedo,
+            normal,
+            position: v.pos,
+            specular,
+            material_id: self.material_id,
+        })
+    }
+}
@@ -1,67 +1,180 @@
-use glm::{Mat4, Vec3};
-use image::{ImageBuffer, Rgba};
-use num::One;
+use glm::{Mat4, Vec2, Vec3};
+use image::Rgba;
+use num::{One, Zero};
 use obj::TexturedVertex;
 
-use super::IShader;
+use crate::{
+    draw::{
+        light::Light,
+        shadow::{ShadowMap, ShadowedLights},
+        texture::{Sampler, Texture, Texture2D},
+    },
+    error::{RenderError, Result},
+    vec4_to_3,
+};
 
+use super::{Fragment, IShader, Interpolate};
+
+/// GouraudShader 的varying
+#[derive(Debug, Clone, Copy)]
+pub struct GouraudVaryings {
+    pub intensity: [Vec3; GouraudShader::MAX_LIGHTS], // 每个光源的光照，在顶点着色器中计算
+    pub uv: Vec2,                                     // 纹理坐标
+    pub pos: Vec3,                                    // 世界坐标，用于阴影查找
+}
+
+impl Interpolate for GouraudVaryings {
+    fn interpolate(v: &[Self; 3], bar: Vec3) -> Self {
+        Self {
+            intensity: <[Vec3; GouraudShader::MAX_LIGHTS]>::interpolate(
+                &v.map(|v| v.intensity),
+                bar,
+            ),
+            uv: Vec2::interpolate(&v.map(|v| v.uv), bar),
+            pos: Vec3::interpolate(&v.map(|v| v.pos), bar),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct GouraudShader<'a> {
     model: &'a obj::Obj<TexturedVertex, u32>,
-    diffuse: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
-    varying_intensity: glm::Vec3, // 三个顶点的光照强度，由顶点着色器写入，由片段着色器读取
-    varying_uv: glm::Mat3,        // 三个顶点的纹理坐标
-    view_port: Mat4,
+    diffuse: Texture2D<'a>,
     projection: Mat4,
     model_view: Mat4,
-    light_dir: Vec3,
+    lights: ShadowedLights<'a>,
+    uniform_model: Mat4, // 模型矩阵，把顶点变换到世界坐标查阴影
+}
+
+impl GouraudShader<'_> {
+    /// 每个光源的光照单独插值，最多支持MAX_LIGHTS个光源
+    pub const MAX_LIGHTS: usize = 8;
 }
 
 impl<'a> GouraudShader<'a> {
+    /// 每个光源的贡献累加，阴影贴图用with_light_shadow设置
+    ///
+    /// 光源超过MAX_LIGHTS个时返回错误
     pub fn new(
         model: &'a obj::Obj<TexturedVertex, u32>,
-        diffuse: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
+        diffuse: &'a Texture,
         model_view: Mat4,
         projection: Mat4,
-        view_port: Mat4,
-        light_dir: Vec3,
-    ) -> Self {
-        Self {
+        lights: &[Light],
+    ) -> Result<Self> {
+        if lights.len() > Self::MAX_LIGHTS {
+            return Err(RenderError::TooManyLights {
+                shader: "gouraud",
+                found: lights.len(),
+                max: Self::MAX_LIGHTS,
+            });
+        }
+        Ok(Self {
             model,
-            varying_intensity: glm::Vec3::one(),
-            view_port,
             projection,
             model_view,
-            light_dir,
-            diffuse,
-            varying_uv: glm::Mat3::one(),
-        }
+            lights: ShadowedLights::new(lights),
+            diffuse: Texture2D::new(diffuse, Sampler::default()),
+            uniform_model: Mat4::one(),
+        })
+    }
+
+    /// 模型在世界中的变换，默认单位矩阵
+    ///
+    /// 用于把法线和阴影查找的位置变换到世界坐标，顶点变换仍然使用构造时传入的矩阵
+    pub fn with_model_matrix(mut self, model_matrix: Mat4) -> Self {
+        self.uniform_model = model_matrix;
+        self
+    }
+
+    /// 贴图的采样方式，默认三线性过滤、平铺
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.diffuse.set_sampler(sampler);
+        self
+    }
+
+    /// 使用阴影贴图计算第一个光源的投射阴影
+    pub fn with_shadow(self, shadow: &'a ShadowMap) -> Self {
+        self.with_light_shadow(0, shadow)
+    }
+
+    /// 使用阴影贴图计算第index个光源的投射阴影，见 ShadowedLights::set_shadow
+    pub fn with_light_shadow(mut self, index: usize, shadow: &'a ShadowMap) -> Self {
+        self.lights.set_shadow(index, shadow);
+        self
     }
 }
 
 impl<'a> IShader for GouraudShader<'a> {
-    fn vertex(&mut self, i_face: usize, nth_vert: usize) -> glm::Vec4 {
-        let i_vert = self.model.indices[i_face * 3 + nth_vert];
-        let vert = self.model.vertices[i_vert as usize];
+    type Varyings = GouraudVaryings;
+    type Output = Rgba<u8>;
+
+    fn vertex(&self, i_vert: usize) -> (glm::Vec4, GouraudVaryings) {
+        let vert = self.model.vertices[i_vert];
         let normal = Vec3::from_array(&vert.normal); // 顶点法向量
         let v = Vec3::from_array(&vert.position); // 顶点位置
-        let uv = Vec3::from_array(&vert.texture); // 纹理坐标
-        let gl_v = self.view_port * self.projection * self.model_view * v.extend(1.);
-        self.varying_intensity[nth_vert] = glm::dot(*normal, self.light_dir).max(0.); // 计算每个顶点的光照强度
-        self.varying_uv.as_array_mut()[nth_vert] = uv.clone(); // 每一列是一个顶点出的纹理坐标
-        gl_v
+        let gl_v = self.projection * self.model_view * v.extend(1.);
+        let normal = glm::normalize(vec4_to_3(self.uniform_model * normal.extend(0.))); // 法线变换到世界坐标(假设没有非均匀缩放)
+        let pos = vec4_to_3(self.uniform_model * v.extend(1.));
+        let mut intensity = [Vec3::zero(); Self::MAX_LIGHTS];
+        for (intensity, (light, _)) in intensity.iter_mut().zip(self.lights.iter()) {
+            let (l, radiance) = light.illuminate(pos);
+            *intensity = radiance * glm::dot(normal, l).max(0.); // 计算每个顶点的光照强度
+        }
+        let varyings = GouraudVaryings {
+            intensity,
+            uv: glm::vec2(vert.texture[0], vert.texture[1]),
+            pos,
+        };
+        (gl_v, varyings)
+    }
+
+    fn fragment(&self, frag: &Fragment<GouraudVaryings>) -> Option<Rgba<u8>> {
+        let v = frag.varyings; // 插值后的强度、纹理坐标和位置
+        let mut intensity = Vec3::zero();
+        for (&light, (_, shadow)) in v.intensity.iter().zip(self.lights.iter()) {
+            intensity = intensity
+                + match shadow {
+                    Some(shadow) => light * (0.3 + 0.7 * shadow.visibility(v.pos)),
+                    None => light,
+                };
+        }
+        // 纹理坐标在屏幕空间的导数用来选择mip层级
+        let px = self
+            .diffuse
+            .sample_grad(v.uv, frag.dfdx().uv, frag.dfdy().uv)
+            * 255.;
+        let r = (px.x * intensity.x) as u8;
+        let g = (px.y * intensity.y) as u8;
+        let b = (px.z * intensity.z) as u8;
+        Some(Rgba([r, g, b, 255]))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageBuffer;
+
+    use super::*;
 
-    fn fragment(&mut self, bar: glm::Vec3, color: &mut image::Rgba<u8>) -> bool {
-        let intensity = glm::dot(self.varying_intensity, bar); // 当前像素的插值强度，重心坐标计算相对三个顶点的强度
-        let uv = self.varying_uv * bar; // 用重心坐标插值当前点的纹理坐标
-        let px = self.diffuse.get_pixel(
-            (uv.x * self.diffuse.width() as f32) as _,
-            (uv.y * self.diffuse.height() as f32) as _,
-        );
-        let r = (px[0] as f32 * intensity) as u8;
-        let g = (px[1] as f32 * intensity) as u8;
-        let b = (px[2] as f32 * intensity) as u8;
-        *color = image::Rgba([r, g, b, 255]);
-        return false; // 不丢弃任何像素
+    #[test]
+    fn rejects_more_than_max_lights() {
+        let model = obj::Obj {
+            name: None,
+            vertices: vec![],
+            indices: vec![],
+        };
+        let diffuse = Texture::new(ImageBuffer::from_pixel(1, 1, Rgba([255; 4])));
+        let lights = [Light::directional(glm::vec3(0., 0., 1.)); GouraudShader::MAX_LIGHTS + 1];
+        let new = |lights| GouraudShader::new(&model, &diffuse, Mat4::one(), Mat4::one(), lights);
+        assert!(new(&lights[..GouraudShader::MAX_LIGHTS]).is_ok());
+        assert!(matches!(
+            new(&lights),
+            Err(RenderError::TooManyLights {
+                found: 9,
+                max: 8,
+                ..
+            })
+        ));
     }
 }
@@ -0,0 +1,386 @@
+use std::f32::consts::PI;
+
+use glm::{GenMat, GenSquareMat, Mat4, Vec3, Vec4};
+use image::Rgba;
+use num::Zero;
+use obj::TexturedVertex;
+
+use crate::{
+    draw::{
+        light::Light,
+        shadow::{ShadowMap, ShadowedLights},
+        texture::{Sampler, Texture, Texture2D},
+    },
+    error::{RenderError, Result},
+    model::DrawBatch,
+    vec4_to_3,
+};
+
+use super::{tangent_space_normal, Fragment, IShader, SurfaceVaryings};
+
+/// 金属度-粗糙度材质，和glTF的pbrMetallicRoughness一致
+///
+/// 每个贴图都可以省略，省略时只使用对应的系数，贴图和系数相乘
+#[derive(Debug, Clone, Copy)]
+pub struct PbrMaterial<'a> {
+    /// 基础颜色贴图(sRGB)，alpha暂不使用
+    pub base_color: Option<&'a Texture>,
+    /// 线性空间的基础颜色
+    pub base_color_factor: Vec4,
+    /// 绿色通道是粗糙度，蓝色通道是金属度(线性)
+    pub metallic_roughness: Option<&'a Texture>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    /// 法线贴图，默认在物体空间，设置切线后在切线空间
+    pub normal: Option<&'a Texture>,
+    /// 切线空间法线xy分量的缩放
+    pub normal_scale: f32,
+    /// 红色通道是环境光遮蔽系数(线性)
+    pub occlusion: Option<&'a Texture>,
+    /// 0表示不使用遮蔽，1表示完全使用
+    pub occlusion_strength: f32,
+    /// 自发光贴图(sRGB)
+    pub emissive: Option<&'a Texture>,
+    /// 线性空间的自发光颜色
+    pub emissive_factor: Vec3,
+}
+
+impl Default for PbrMaterial<'_> {
+    /// glTF规定的默认值，没有贴图时是白色、完全金属、完全粗糙、不发光
+    fn default() -> Self {
+        Self {
+            base_color: None,
+            base_color_factor: glm::vec4(1., 1., 1., 1.),
+            metallic_roughness: None,
+            metallic_factor: 1.,
+            roughness_factor: 1.,
+            normal: None,
+            normal_scale: 1.,
+            occlusion: None,
+            occlusion_strength: 1.,
+            emissive: None,
+            emissive_factor: glm::vec3(0., 0., 0.),
+        }
+    }
+}
+
+impl<'a> PbrMaterial<'a> {
+    /// 模型一个绘制批次的材质，命令行和场景共用
+    ///
+    /// 基础颜色和法线优先使用批次的MTL贴图，没有时用fallback里的，
+    /// 其他贴图和系数取自fallback，有自发光贴图时自发光系数取1
+    pub fn for_batch(batch: &'a DrawBatch, fallback: PbrMaterial<'a>) -> Self {
+        Self {
+            base_color: Some(batch.diffuse(fallback.base_color)),
+            normal: batch.normal(fallback.normal),
+            // 没有贴图时只用系数，系数为0不发光
+            emissive_factor: match fallback.emissive {
+                Some(_) => glm::vec3(1., 1., 1.),
+                None => fallback.emissive_factor,
+            },
+            ..fallback
+        }
+    }
+}
+
+/// 基于物理的着色，Cook-Torrance高光(GGX法线分布、Smith几何遮蔽、Schlick菲涅尔)加Lambert漫反射
+///
+/// 光照在世界坐标和线性空间中计算，输出前编码成sRGB
+#[derive(Clone)]
+pub struct PbrShader<'a> {
+    model: &'a obj::Obj<TexturedVertex, u32>,
+    base_color: Option<Texture2D<'a>>,
+    metallic_roughness: Option<Texture2D<'a>>,
+    normal: Option<Texture2D<'a>>,
+    occlusion: Option<Texture2D<'a>>,
+    emissive: Option<Texture2D<'a>>,
+    material: PbrMaterial<'a>,
+    uniform_model: Mat4,    // 模型矩阵
+    uniform_model_it: Mat4, // 模型矩阵的逆转置，变换法线
+    uniform_vp: Mat4,       // 世界坐标 -> 裁剪空间 projection*view
+    eye: Vec3,              // 世界坐标的摄像机位置
+    lights: ShadowedLights<'a>,
+    ambient: Vec3,                     // 环境光，线性空间
+    tangents: Option<&'a [glm::Vec4]>, // 每个顶点的切线，Some时法线贴图在切线空间
+}
+
+impl<'a> PbrShader<'a> {
+    /// 默认的环境光
+    pub const DEFAULT_AMBIENT: f32 = 0.03;
+
+    /// eye是世界坐标的摄像机位置，每个光源的贡献累加，阴影贴图用with_light_shadow设置
+    ///
+    /// 模型矩阵不可逆时无法变换法线，返回错误
+    pub fn new(
+        model: &'a obj::Obj<TexturedVertex, u32>,
+        material: PbrMaterial<'a>,
+        uniform_model: Mat4,
+        uniform_vp: Mat4,
+        eye: Vec3,
+        lights: &[Light],
+    ) -> Result<Self> {
+        let uniform_model_it = uniform_model
+            .inverse()
+            .ok_or(RenderError::SingularMatrix("model"))?
+            .transpose();
+        let texture = |t: Option<&'a Texture>| t.map(|t| Texture2D::new(t, Sampler::default()));
+        let ambient = Self::DEFAULT_AMBIENT;
+        Ok(Self {
+            model,
+            base_color: texture(material.base_color),
+            metallic_roughness: texture(material.metallic_roughness),
+            normal: texture(material.normal),
+            occlusion: texture(material.occlusion),
+            emissive: texture(material.emissive),
+            material,
+            uniform_model,
+            uniform_model_it,
+            uniform_vp,
+            eye,
+            lights: ShadowedLights::new(lights),
+            ambient: glm::vec3(ambient, ambient, ambient),
+            tangents: None,
+        })
+    }
+
+    /// 所有贴图的采样方式，默认三线性过滤、平铺
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        for texture in [
+            &mut self.base_color,
+            &mut self.metallic_roughness,
+            &mut self.normal,
+            &mut self.occlusion,
+            &mut self.emissive,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            texture.set_sampler(sampler);
+        }
+        self
+    }
+
+    /// 法线贴图按切线空间解释，默认是物体空间，见 PhongShader::with_tangents
+    pub fn with_tangents(mut self, tangents: &'a [glm::Vec4]) -> Self {
+        self.tangents = Some(tangents);
+        self
+    }
+
+    /// 均匀的环境光颜色(线性空间)，乘上基础颜色和遮蔽系数
+    pub fn with_ambient(mut self, ambient: Vec3) -> Self {
+        self.ambient = ambient;
+        self
+    }
+
+    /// 使用阴影贴图计算第一个光源的投射阴影
+    pub fn with_shadow(self, shadow: &'a ShadowMap) -> Self {
+        self.with_light_shadow(0, shadow)
+    }
+
+    /// 使用阴影贴图计算第index个光源的投射阴影，见 ShadowedLights::set_shadow
+    pub fn with_light_shadow(mut self, index: usize, shadow: &'a ShadowMap) -> Self {
+        self.lights.set_shadow(index, shadow);
+        self
+    }
+}
+
+impl<'a> IShader for PbrShader<'a> {
+    type Varyings = SurfaceVaryings;
+    type Output = Rgba<u8>;
+
+    fn vertex(&self, i_vert: usize) -> (glm::Vec4, SurfaceVaryings) {
+        let vert = self.model.vertices[i_vert];
+        let world = self.uniform_model * Vec3::from_array(&vert.position).extend(1.);
+        let normal = Vec3::from_array(&vert.normal);
+        let mut varyings = SurfaceVaryings {
+            uv: glm::vec2(vert.texture[0], vert.texture[1]),
+            pos: vec4_to_3(world),
+            normal: vec4_to_3(self.uniform_model_it * normal.extend(0.)),
+            tangent: Vec4::zero(),
+        };
+        if let Some(tangents) = self.tangents {
+            let tangent = tangents[i_vert];
+            varyings.tangent =
+                vec4_to_3(self.uniform_model * vec4_to_3(tangent).extend(0.)).extend(tangent.w);
+        }
+        (self.uniform_vp * world, varyings)
+    }
+
+    fn fragment(&self, frag: &Fragment<SurfaceVaryings>) -> Option<Rgba<u8>> {
+        let v = frag.varyings;
+        let m = &self.material;
+        let (uv, duv_dx, duv_dy) = (v.uv, frag.dfdx().uv, frag.dfdy().uv);
+        let sample = |t: Option<Texture2D>| t.map(|t| t.sample_grad(uv, duv_dx, duv_dy));
+
+        let base_color = match sample(self.base_color) {
+            Some(c) => srgb_to_linear(vec4_to_3(c)),
+            None => glm::vec3(1., 1., 1.),
+        } * vec4_to_3(m.base_color_factor);
+        let (metallic, roughness) = match sample(self.metallic_roughness) {
+            Some(c) => (c.z * m.metallic_factor, c.y * m.roughness_factor),
+            None => (m.metallic_factor, m.roughness_factor),
+        };
+        let metallic = metallic.clamp(0., 1.);
+        // 粗糙度太小时点光源的高光会退化成一个点
+        let roughness = roughness.clamp(0.04, 1.);
+        let occlusion = match sample(self.occlusion) {
+            Some(c) => 1. + m.occlusion_strength * (c.x - 1.),
+            None => 1.,
+        };
+        let emissive = match sample(self.emissive) {
+            Some(c) => srgb_to_linear(vec4_to_3(c)),
+            None => glm::vec3(1., 1., 1.),
+        } * m.emissive_factor;
+
+        let n = self.normal(&v, sample(self.normal));
+        let to_eye = glm::normalize(self.eye - v.pos);
+        // 背面的像素把法线翻过来
+        let n = if glm::dot(n, to_eye) < 0. { -n } else { n };
+
+        // 电介质的反射率取4%，金属的反射率就是基础颜色
+        let dielectric = glm::vec3(0.04, 0.04, 0.04);
+        let f0 = dielectric + (base_color - dielectric) * metallic;
+        let n_dot_v = glm::dot(n, to_eye).max(1e-4);
+        let mut color = Vec3::zero();
+        for (light, shadow) in self.lights.iter() {
+            let (l, radiance) = light.illuminate(v.pos);
+            let n_dot_l = glm::dot(n, l);
+            if n_dot_l <= 0. {
+                continue;
+            }
+            let h = glm::normalize(to_eye + l);
+            let d = distribution_ggx(glm::dot(n, h).max(0.), roughness);
+            let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+            let f = fresnel_schlick(glm::dot(h, to_eye).max(0.), f0);
+            let specular = f * (d * g / (4. * n_dot_v * n_dot_l).max(1e-4));
+            // 被反射掉的能量不再参与漫反射，金属没有漫反射
+            let kd = (glm::vec3(1., 1., 1.) - f) * (1. - metallic);
+            let diffuse = kd * base_color / PI;
+            let visibility = shadow.map_or(1., |s| s.visibility(v.pos));
+            color = color + (diffuse + specular) * radiance * (n_dot_l * visibility);
+        }
+        color = color + self.ambient * base_color * occlusion + emissive;
+
+        let c = linear_to_srgb(color);
+        let to_u8 = |x: f32| (x.clamp(0., 1.) * 255.).round() as u8;
+        Some(Rgba([to_u8(c.x), to_u8(c.y), to_u8(c.z), 255]))
+    }
+}
+
+impl<'a> PbrShader<'a> {
+    // 像素处世界坐标的单位法线，nm是法线贴图的采样结果
+    fn normal(&self, v: &SurfaceVaryings, nm: Option<Vec4>) -> Vec3 {
+        let normal = glm::normalize(v.normal);
+        let nm = match nm {
+            Some(nm) => vec4_to_3(nm) * 2. - 1.,
+            None => return normal,
+        };
+        match self.tangents {
+            Some(_) => {
+                let scale = self.material.normal_scale;
+                let nm = glm::vec3(nm.x * scale, nm.y * scale, nm.z);
+                tangent_space_normal(normal, v.tangent, nm)
+            }
+            None => glm::normalize(vec4_to_3(self.uniform_model_it * nm.extend(0.))),
+        }
+    }
+}
+
+// GGX/Trowbridge-Reitz 法线分布，alpha取粗糙度的平方
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a2 = roughness.powi(4);
+    let d = n_dot_h * n_dot_h * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+// Schlick-GGX 近似的单方向遮蔽，直接光照时 k=(r+1)^2/8
+fn geometry_schlick_ggx(n_dot_x: f32, roughness: f32) -> f32 {
+    let k = (roughness + 1.).powi(2) / 8.;
+    n_dot_x / (n_dot_x * (1. - k) + k)
+}
+
+// Smith 方法把视线和光线两个方向的遮蔽相乘
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness)
+}
+
+fn fresnel_schlick(cos: f32, f0: Vec3) -> Vec3 {
+    let t = (1. - cos).clamp(0., 1.).powi(5);
+    f0 + (glm::vec3(1., 1., 1.) - f0) * t
+}
+
+fn srgb_to_linear(c: Vec3) -> Vec3 {
+    let f = |c: f32| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    glm::vec3(f(c.x), f(c.y), f(c.z))
+}
+
+fn linear_to_srgb(c: Vec3) -> Vec3 {
+    let f = |c: f32| {
+        let c = c.clamp(0., 1.);
+        if c <= 0.003_130_8 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1. / 2.4) - 0.055
+        }
+    };
+    glm::vec3(f(c.x), f(c.y), f(c.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        let d = actual - expected;
+        assert!(
+            d.x.abs() < 1e-5 && d.y.abs() < 1e-5 && d.z.abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn fresnel_endpoints() {
+        let f0 = glm::vec3(0.04, 0.5, 0.9);
+        assert_near(fresnel_schlick(1., f0), f0);
+        assert_near(fresnel_schlick(0., f0), glm::vec3(1., 1., 1.));
+    }
+
+    #[test]
+    fn geometry_is_one_at_normal_incidence() {
+        for roughness in [0.04, 0.5, 1.] {
+            assert!((geometry_smith(1., 1., roughness) - 1.).abs() < 1e-6);
+            assert!(geometry_smith(0.3, 0.7, roughness) < 1.);
+        }
+    }
+
+    #[test]
+    fn distribution_peaks_at_half_vector() {
+        for roughness in [0.1, 0.5, 0.9] {
+            let peak = distribution_ggx(1., roughness);
+            for n_dot_h in [0., 0.5, 0.9, 0.99] {
+                assert!(distribution_ggx(n_dot_h, roughness) < peak);
+            }
+        }
+        // 越光滑峰值越高
+        assert!(distribution_ggx(1., 0.1) > distribution_ggx(1., 0.5));
+    }
+
+    #[test]
+    fn srgb_round_trip() {
+        for i in 0..=20 {
+            let x = i as f32 / 20.;
+            let c = glm::vec3(x, x * 0.5, 1. - x);
+            assert_near(linear_to_srgb(srgb_to_linear(c)), c);
+        }
+        // 中灰的sRGB值大约是线性的0.214
+        assert!((srgb_to_linear(glm::vec3(0.5, 0.5, 0.5)).x - 0.214).abs() < 1e-3);
+    }
+}
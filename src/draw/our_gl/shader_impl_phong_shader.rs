@@ -1,98 +1,301 @@
-use glm::{GenMat, GenSquareMat, Mat4, Vec3};
-use image::{ImageBuffer, Rgba};
-use num::One;
+use glm::{GenMat, GenSquareMat, Mat4, Vec3, Vec4};
+use image::Rgba;
+use num::Zero;
 use obj::TexturedVertex;
 
-use crate::vec4_to_3;
+use crate::{
+    draw::{
+        light::Light,
+        shadow::{ShadowMap, ShadowedLights},
+        target::Plane,
+        texture::{Sampler, Texture, Texture2D},
+    },
+    error::{RenderError, Result},
+    vec4_to_3,
+};
 
-use super::IShader;
+use super::{tangent_space_normal, Fragment, IShader, SurfaceVaryings};
 
+#[derive(Clone)]
 pub struct PhongShader<'a> {
     model: &'a obj::Obj<TexturedVertex, u32>,
-    diffuse: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
-    diffuse_nm: &'a ImageBuffer<Rgba<u8>, Vec<u8>>, // 法线贴图
-    diffuse_spec: &'a ImageBuffer<Rgba<u8>, Vec<u8>>, // 高光贴图
-    varying_uv: glm::Mat3,                          // 三个顶点的纹理坐标
-    uniform_m: Mat4,                                // 模型的变换矩阵m projection*model_view
-    uniform_mit: Mat4,                              // m的逆转置矩阵 m.inverse().transpose()
-    light_dir: Vec3,
+    diffuse: Texture2D<'a>,
+    diffuse_nm: Texture2D<'a>,   // 法线贴图
+    diffuse_spec: Texture2D<'a>, // 高光贴图
+    uniform_model: Mat4,         // 模型矩阵
+    uniform_model_it: Mat4,      // 模型矩阵的逆转置，变换法线
+    uniform_vp: Mat4,            // 世界坐标 -> 裁剪空间 projection*view
+    eye: Vec3,                   // 世界坐标的摄像机位置
+    lights: ShadowedLights<'a>,
+    tangents: Option<&'a [glm::Vec4]>, // 每个顶点的切线，Some时法线贴图在切线空间
+    ambient_occlusion: Option<&'a Plane<f32>>, // 每个像素的环境光遮蔽系数
+    occlusion_map: Option<Texture2D<'a>>, // 烘焙的环境光遮蔽贴图，取红色通道
 }
 
 impl<'a> PhongShader<'a> {
+    /// eye是世界坐标的摄像机位置，每个光源的贡献累加，阴影贴图用with_light_shadow设置
+    ///
+    /// 光照在世界坐标中计算，模型矩阵不可逆时无法变换法线，返回错误
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         model: &'a obj::Obj<TexturedVertex, u32>,
-        diffuse: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
-        diffuse_nm: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
-        diffuse_spec: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
-        uniform_m: Mat4,
-        light_dir: Vec3,
-    ) -> Self {
-        Self {
+        diffuse: &'a Texture,
+        diffuse_nm: &'a Texture,
+        diffuse_spec: &'a Texture,
+        uniform_model: Mat4,
+        uniform_vp: Mat4,
+        eye: Vec3,
+        lights: &[Light],
+    ) -> Result<Self> {
+        let uniform_model_it = uniform_model
+            .inverse()
+            .ok_or(RenderError::SingularMatrix("model"))?
+            .transpose();
+        Ok(Self {
             model,
-            light_dir,
-            diffuse,
-            varying_uv: glm::Mat3::one(),
-            uniform_m,
-            uniform_mit: uniform_m.inverse().unwrap().transpose(),
-            diffuse_nm,
-            diffuse_spec,
+            lights: ShadowedLights::new(lights),
+            diffuse: Texture2D::new(diffuse, Sampler::default()),
+            diffuse_nm: Texture2D::new(diffuse_nm, Sampler::default()),
+            diffuse_spec: Texture2D::new(diffuse_spec, Sampler::default()),
+            uniform_model,
+            uniform_model_it,
+            uniform_vp,
+            eye,
+            tangents: None,
+            ambient_occlusion: None,
+            occlusion_map: None,
+        })
+    }
+
+    /// 所有贴图的采样方式，默认三线性过滤、平铺
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.diffuse.set_sampler(sampler);
+        self.diffuse_nm.set_sampler(sampler);
+        self.diffuse_spec.set_sampler(sampler);
+        if let Some(occlusion_map) = &mut self.occlusion_map {
+            occlusion_map.set_sampler(sampler);
         }
+        self
     }
-}
 
-impl<'a> IShader for PhongShader<'a> {
-    fn vertex(&mut self, i_face: usize, nth_vert: usize) -> glm::Vec4 {
-        let i_vert = self.model.indices[i_face * 3 + nth_vert];
-        let vert = self.model.vertices[i_vert as usize];
-        let normal = Vec3::from_array(&vert.normal); // 顶点法向量
-        let v = Vec3::from_array(&vert.position); // 顶点位置
-        let uv = Vec3::from_array(&vert.texture); // 纹理坐标
-        let gl_v = self.uniform_m * v.extend(1.);
-        self.varying_uv.as_array_mut()[nth_vert] = uv.clone(); // 每一列是一个顶点出的纹理坐标
-        gl_v
+    /// 法线贴图按切线空间解释，默认是物体空间
+    ///
+    /// tangents是每个顶点的切线，长度和顶点数相同，见 model::generate_tangents
+    pub fn with_tangents(mut self, tangents: &'a [glm::Vec4]) -> Self {
+        self.tangents = Some(tangents);
+        self
     }
 
-    fn fragment(&mut self, bar: glm::Vec3, color: &mut image::Rgba<u8>) -> bool {
-        let uv = self.varying_uv * bar; // 用重心坐标插值当前点的纹理坐标
-        let px = self.diffuse.get_pixel(
-            (uv.x * self.diffuse.width() as f32) as _,
-            (uv.y * self.diffuse.height() as f32) as _,
-        );
-        let nm_px = self.diffuse_nm.get_pixel(
-            (uv.x * self.diffuse_nm.width() as f32) as _,
-            (uv.y * self.diffuse_nm.height() as f32) as _,
-        );
-        let spec_px = self.diffuse_spec.get_pixel(
-            (uv.x * self.diffuse_spec.width() as f32) as _,
-            (uv.y * self.diffuse_spec.height() as f32) as _,
-        );
-        let spec_v = spec_px[0] as f32 / 1.; // 光泽值, 这个值越小越反射范围越大，越不光泽，越大越有光泽
+    /// 使用阴影贴图计算第一个光源的投射阴影
+    pub fn with_shadow(self, shadow: &'a ShadowMap) -> Self {
+        self.with_light_shadow(0, shadow)
+    }
 
-        let mut n = Vec3::from_array(&[nm_px[0] as _, nm_px[1] as _, nm_px[2] as _]).clone(); // 从贴图中加载法向量
-        n.as_array_mut()
-            .iter_mut()
-            .for_each(|v| *v = *v / 255. * 2. - 1.); // tga图像中[0,255], 转换到[-1,-1]
+    /// 使用阴影贴图计算第index个光源的投射阴影，见 ShadowedLights::set_shadow
+    pub fn with_light_shadow(mut self, index: usize, shadow: &'a ShadowMap) -> Self {
+        self.lights.set_shadow(index, shadow);
+        self
+    }
 
-        let n = self.uniform_mit * n.extend(0.); // 法线映射 注意向量转换位齐次坐标是填0
-        let n = glm::normalize(vec4_to_3(n)); // 齐次坐标投影回3d 注意向量不需要除w分量
+    /// 环境光乘上所在像素的遮蔽系数，尺寸和渲染分辨率相同，见 ssao::Ssao
+    pub fn with_ambient_occlusion(mut self, ambient_occlusion: &'a Plane<f32>) -> Self {
+        self.ambient_occlusion = Some(ambient_occlusion);
+        self
+    }
 
-        let l = self.uniform_m * self.light_dir.extend(0.); // 映射光照方向
-        let l = glm::normalize(vec4_to_3(l));
+    /// 环境光乘上按uv采样的烘焙遮蔽系数，见 ao_bake::AoBake，可以和SSAO同时使用
+    pub fn with_occlusion_map(mut self, occlusion_map: &'a Texture) -> Self {
+        let sampler = self.diffuse.sampler();
+        self.occlusion_map = Some(Texture2D::new(occlusion_map, sampler));
+        self
+    }
+}
 
-        let r = glm::normalize(n * (glm::dot(n, l) * 2.) - l); // 反射光方向
+impl<'a> PhongShader<'a> {
+    // 像素处的环境光遮蔽系数，屏幕空间和烘焙的相乘，都没有设置时为1
+    fn occlusion(&self, frag: &Fragment<SurfaceVaryings>) -> f32 {
+        let baked = match &self.occlusion_map {
+            Some(map) => {
+                let (uv, duv_dx, duv_dy) = (frag.varyings.uv, frag.dfdx().uv, frag.dfdy().uv);
+                map.sample_grad(uv, duv_dx, duv_dy).x
+            }
+            None => 1.,
+        };
+        let screen = match self.ambient_occlusion {
+            Some(ao) => {
+                let x = (frag.frag_coord.x as u32).min(ao.width() - 1);
+                let y = (frag.frag_coord.y as u32).min(ao.height() - 1);
+                ao.get(x, y)
+            }
+            None => 1.,
+        };
+        baked * screen
+    }
+}
 
-        let spec = glm::pow(r.z.max(0.), spec_v); // 我们从z轴看, dot(v,r)
-        let diff = glm::dot(n, l).max(0.);
+impl<'a> IShader for PhongShader<'a> {
+    type Varyings = SurfaceVaryings;
+    type Output = Rgba<u8>;
 
-        let arg_ambient = 5.; // 环境光
+    fn vertex(&self, i_vert: usize) -> (glm::Vec4, SurfaceVaryings) {
+        let vert = self.model.vertices[i_vert];
+        let world = self.uniform_model * Vec3::from_array(&vert.position).extend(1.);
+        let mut varyings = SurfaceVaryings {
+            uv: glm::vec2(vert.texture[0], vert.texture[1]),
+            pos: vec4_to_3(world),
+            normal: Vec3::zero(),
+            tangent: Vec4::zero(),
+        };
+        if let Some(tangents) = self.tangents {
+            let normal = Vec3::from_array(&vert.normal);
+            let tangent = tangents[i_vert];
+            varyings.normal = vec4_to_3(self.uniform_model_it * normal.extend(0.));
+            varyings.tangent =
+                vec4_to_3(self.uniform_model * vec4_to_3(tangent).extend(0.)).extend(tangent.w);
+        }
+        (self.uniform_vp * world, varyings)
+    }
+
+    fn fragment(&self, frag: &Fragment<SurfaceVaryings>) -> Option<Rgba<u8>> {
+        let v = frag.varyings;
+        // 纹理坐标以及它在屏幕空间的导数
+        let (uv, duv_dx, duv_dy) = (v.uv, frag.dfdx().uv, frag.dfdy().uv);
+        let px = self.diffuse.sample_grad(uv, duv_dx, duv_dy) * 255.;
+        let nm_px = self.diffuse_nm.sample_grad(uv, duv_dx, duv_dy);
+        let spec_px = self.diffuse_spec.sample_grad(uv, duv_dx, duv_dy);
+        let spec_v = spec_px.x * 255.; // 光泽值, 这个值越小越反射范围越大，越不光泽，越大越有光泽
+
+        let n = vec4_to_3(nm_px) * 2. - 1.; // 从贴图中加载法向量 [0,1]转换到[-1,1]
+
+        let n = match self.tangents {
+            // 切线空间: 用插值后的法线和切线构造TBN
+            Some(_) => tangent_space_normal(v.normal, v.tangent, n),
+            // 物体空间: 用模型矩阵的逆转置变换到世界坐标
+            None => {
+                let n = self.uniform_model_it * n.extend(0.); // 法线映射 注意向量转换位齐次坐标是填0
+                glm::normalize(vec4_to_3(n)) // 齐次坐标投影回3d 注意向量不需要除w分量
+            }
+        };
+        let view = glm::normalize(self.eye - v.pos); // 指向摄像机的方向
+
+        let arg_ambient = 5. * self.occlusion(frag); // 环境光
         let arg_diffuse = 1.; // 漫反射光
         let arg_specular = 0.6; // 镜面反射光
-        let intensity = glm::dot(n, l);
 
-        let r = (arg_ambient + px[0] as f32 * (arg_diffuse * diff + arg_specular * spec)) as u8;
-        let g = (arg_ambient + px[1] as f32 * (arg_diffuse * diff + arg_specular * spec)) as u8;
-        let b = (arg_ambient + px[2] as f32 * (arg_diffuse * diff + arg_specular * spec)) as u8;
-        *color = image::Rgba([r, g, b, 255]);
-        return false; // 不丢弃任何像素
+        let mut light = Vec3::zero();
+        for (source, shadow) in self.lights.iter() {
+            let (l, radiance) = source.illuminate(v.pos); // 世界坐标，和n在同一个空间
+
+            let r = glm::normalize(n * (glm::dot(n, l) * 2.) - l); // 反射光方向
+
+            let spec = glm::pow(glm::dot(view, r).max(0.), spec_v);
+            let diff = glm::dot(n, l).max(0.);
+
+            // 阴影中的像素保留30%的光照
+            let shadow = match shadow {
+                Some(shadow) => 0.3 + 0.7 * shadow.visibility(v.pos),
+                None => 1.,
+            };
+            light = light + radiance * (shadow * (arg_diffuse * diff + arg_specular * spec));
+        }
+
+        let r = (arg_ambient + px.x * light.x) as u8;
+        let g = (arg_ambient + px.y * light.y) as u8;
+        let b = (arg_ambient + px.z * light.z) as u8;
+        Some(Rgba([r, g, b, 255]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageBuffer;
+    use num::One;
+
+    use super::*;
+
+    fn solid(color: [u8; 3]) -> Texture {
+        let [r, g, b] = color;
+        Texture::new(ImageBuffer::from_pixel(1, 1, Rgba([r, g, b, 255])))
+    }
+
+    fn triangle(positions: [[f32; 3]; 3], normal: [f32; 3]) -> obj::Obj<TexturedVertex, u32> {
+        obj::Obj {
+            name: None,
+            vertices: positions
+                .iter()
+                .map(|&position| TexturedVertex {
+                    position,
+                    normal,
+                    texture: [0.5, 0.5, 0.],
+                })
+                .collect(),
+            indices: vec![0, 1, 2],
+        }
+    }
+
+    // 三角形重心处的颜色
+    fn shade(shader: &PhongShader) -> Rgba<u8> {
+        let varyings = [0, 1, 2].map(|i| shader.vertex(i).1);
+        let bar = glm::vec3(1. / 3., 1. / 3., 1. / 3.);
+        let zero = Vec3::zero();
+        let frag = Fragment::new(&varyings, bar, zero, zero, glm::vec2(0., 0.));
+        shader.fragment(&frag).unwrap()
+    }
+
+    #[test]
+    fn model_rotation_does_not_move_lights() {
+        let diffuse = solid([200, 180, 160]);
+        let specular = solid([20, 20, 20]);
+        let lights = [
+            Light::directional(glm::vec3(1., 1., 1.)),
+            Light::point(glm::vec3(-1., 0.5, 2.)),
+        ];
+        let eye = glm::vec3(0.5, 0., 3.);
+        let vp = Mat4::one();
+
+        // 世界坐标中朝向+z的三角形，物体空间就是世界空间
+        let flat = triangle([[-1., -1., 0.], [1., -1., 0.], [0., 1., 0.]], [0., 0., 1.]);
+        let nm_flat = solid([128, 128, 255]);
+        let expected = shade(
+            &PhongShader::new(
+                &flat,
+                &diffuse,
+                &nm_flat,
+                &specular,
+                Mat4::one(),
+                vp,
+                eye,
+                &lights,
+            )
+            .unwrap(),
+        );
+
+        // 同一个三角形在物体空间朝向+x，模型矩阵把+x转到+z，世界中的朝向不变
+        #[rustfmt::skip]
+        let rotation = glm::mat4(
+            0., 0., 1., 0.,
+            0., 1., 0., 0.,
+            -1., 0., 0., 0.,
+            0., 0., 0., 1.,
+        );
+        let rotated = triangle([[0., -1., 1.], [0., -1., -1.], [0., 1., 0.]], [1., 0., 0.]);
+        let nm_rotated = solid([255, 128, 128]);
+        let actual = shade(
+            &PhongShader::new(
+                &rotated,
+                &diffuse,
+                &nm_rotated,
+                &specular,
+                rotation,
+                vp,
+                eye,
+                &lights,
+            )
+            .unwrap(),
+        );
+
+        for (a, e) in actual.0.iter().zip(&expected.0) {
+            assert!(a.abs_diff(*e) <= 1, "{:?} != {:?}", actual, expected);
+        }
     }
 }
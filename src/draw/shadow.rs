@@ -0,0 +1,176 @@
+use glm::{Mat4, Vec3};
+use image::{ImageBuffer, Rgba};
+use num::One;
+use obj::TexturedVertex;
+
+use crate::{error::Result, model::check_mesh, v4p2v3};
+
+use super::{
+    depth::{DepthBuffer, DepthFunc},
+    light::Light,
+    msaa::SampleCount,
+    our_gl::{shader_impl_shadow_shader::ShadowShader, IShader},
+    shadow_filter::{self, ShadowFilter},
+    triangle_with_shader, viewport, RasterizerState,
+};
+
+/// 阴影贴图
+///
+/// 第一遍从光源位置渲染深度，第二遍着色时把片段变换到光源的屏幕空间和深度比较
+pub struct ShadowMap {
+    depth: DepthBuffer<f32>,
+    model_view: Mat4,
+    projection: Mat4,
+    view_port: Mat4,
+    uniform_m: Mat4, // 世界坐标 -> 光源屏幕坐标 view_port*projection*model_view
+    bias: f32,       // 深度偏移，防止阴影粉刺(shadow acne)
+    filter: ShadowFilter,
+}
+
+impl ShadowMap {
+    pub const DEFAULT_BIAS: f32 = 0.01;
+
+    /// model_view 以光源为摄像机的变换矩阵
+    pub fn new(
+        width: u32,
+        height: u32,
+        model_view: Mat4,
+        projection: Mat4,
+        view_port: Mat4,
+        bias: f32,
+    ) -> Self {
+        Self {
+            // 与主渲染一样，深度越大离光源越近
+            depth: DepthBuffer::new(width, height, DepthFunc::GreaterEqual, 0.),
+            model_view,
+            projection,
+            view_port,
+            uniform_m: view_port * projection * model_view,
+            bias,
+            filter: ShadowFilter::Hard,
+        }
+    }
+
+    /// 覆盖整张贴图的视口和 Light::shadow_view 给出的变换，
+    /// center和radius是需要投射阴影的物体的包围球
+    pub fn for_light(
+        light: &Light,
+        center: Vec3,
+        radius: f32,
+        width: u32,
+        height: u32,
+        bias: f32,
+    ) -> Self {
+        let (model_view, projection) = light.shadow_view(center, radius);
+        let view_port = viewport(0, 0, width as i32, height as i32);
+        Self::new(width, height, model_view, projection, view_port, bias)
+    }
+
+    pub fn bias(&self) -> f32 {
+        self.bias
+    }
+
+    pub fn set_bias(&mut self, bias: f32) {
+        self.bias = bias;
+    }
+
+    pub fn filter(&self) -> ShadowFilter {
+        self.filter
+    }
+
+    /// 设置采样方式，PCF/PCSS可以得到软阴影
+    pub fn set_filter(&mut self, filter: ShadowFilter) {
+        self.filter = filter;
+    }
+
+    pub fn depth_buffer(&self) -> &DepthBuffer<f32> {
+        &self.depth
+    }
+
+    pub fn depth_buffer_mut(&mut self) -> &mut DepthBuffer<f32> {
+        &mut self.depth
+    }
+
+    /// 世界坐标到光源屏幕坐标的变换
+    pub fn light_space_transform(&self) -> Mat4 {
+        self.uniform_m
+    }
+
+    /// 阴影pass，把模型从光源视角的深度写入阴影贴图
+    pub fn render(&mut self, model: &obj::Obj<TexturedVertex, u32>) -> Result<()> {
+        self.render_transformed(model, Mat4::one())
+    }
+
+    /// 同render，模型先经过model_matrix变换到世界坐标，场景里有多个物体时使用
+    pub fn render_transformed(
+        &mut self,
+        model: &obj::Obj<TexturedVertex, u32>,
+        model_matrix: Mat4,
+    ) -> Result<()> {
+        check_mesh(model)?;
+        let shader = ShadowShader::new(
+            model,
+            self.model_view * model_matrix,
+            self.projection,
+            self.view_port,
+        );
+        // 颜色输出用不上，只要深度
+        let mut image = ImageBuffer::<Rgba<u8>, _>::new(self.depth.width(), self.depth.height());
+        let vertices: Vec<_> = (0..model.vertices.len())
+            .map(|i| shader.vertex(i))
+            .collect();
+        for face in model.indices.chunks_exact(3) {
+            triangle_with_shader(
+                [0, 1, 2].map(|j| vertices[face[j] as usize]),
+                &self.view_port,
+                &RasterizerState::default(),
+                SampleCount::X1,
+                &shader,
+                &mut image,
+                &mut self.depth,
+            );
+        }
+        Ok(())
+    }
+
+    /// 世界坐标pos处的可见度，范围[0,1]，1表示被光照到，0表示完全在阴影中
+    ///
+    /// 按设置的采样方式过滤，任何着色器都可以在片段着色器里调用
+    pub fn visibility(&self, pos: Vec3) -> f32 {
+        let p = v4p2v3(self.uniform_m * pos.extend(1.));
+        // 偏移方向取决于深度比较方式，总是往靠近光源的方向偏
+        let depth = match self.depth.func() {
+            DepthFunc::Less | DepthFunc::LessEqual => p.z - self.bias,
+            _ => p.z + self.bias,
+        };
+        shadow_filter::sample(self.filter, &self.depth, p.x, p.y, depth)
+    }
+}
+
+/// 着色器使用的光源列表，每个光源可以有自己的阴影贴图
+///
+/// phong、gouraud、pbr着色器都嵌入这个结构，with_shadow/with_light_shadow 转发到这里
+#[derive(Clone, Default)]
+pub struct ShadowedLights<'a> {
+    lights: Vec<(Light, Option<&'a ShadowMap>)>, // 光源和它的阴影贴图，None表示不计算阴影
+}
+
+impl<'a> ShadowedLights<'a> {
+    /// 所有光源都不计算阴影
+    pub fn new(lights: &[Light]) -> Self {
+        Self {
+            lights: lights.iter().map(|&light| (light, None)).collect(),
+        }
+    }
+
+    /// 第index个光源使用shadow计算投射阴影，见 ShadowMap::for_light，超出范围时忽略
+    pub fn set_shadow(&mut self, index: usize, shadow: &'a ShadowMap) {
+        if let Some(light) = self.lights.get_mut(index) {
+            light.1 = Some(shadow);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Light, Option<&'a ShadowMap>)> + '_ {
+        self.lights.iter().copied()
+    }
+}
@@ -0,0 +1,177 @@
+use super::depth::{DepthBuffer, DepthFunc};
+
+/// PCF 采样核
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcfKernel {
+    /// 3x3 网格
+    K3x3,
+    /// 5x5 网格
+    K5x5,
+    /// 16个点的泊松圆盘
+    Poisson,
+}
+
+// 单位圆内的泊松分布采样点
+const POISSON_DISK: [(f32, f32); 16] = [
+    (-0.942_016_2, -0.399_062_16),
+    (0.945_586_1, -0.768_907_25),
+    (-0.094_184_1, -0.929_388_7),
+    (0.344_959_38, 0.293_877_6),
+    (-0.915_885_8, 0.457_714_32),
+    (-0.815_442_3, -0.879_124_64),
+    (-0.382_775_43, 0.276_768_45),
+    (0.974_844, 0.756_483_8),
+    (0.443_233_25, -0.975_115_5),
+    (0.537_429_8, -0.473_734_2),
+    (-0.264_969_1, -0.418_930_23),
+    (0.791_975_1, 0.190_901_88),
+    (-0.241_888_4, 0.997_065_07),
+    (-0.814_099_6, 0.914_375_9),
+    (0.199_841_26, 0.786_413_67),
+    (0.143_831_61, -0.141_007_9),
+];
+
+impl PcfKernel {
+    /// 采样点偏移，缩放到单位半径内
+    pub fn offsets(self) -> &'static [(f32, f32)] {
+        match self {
+            PcfKernel::K3x3 => &GRID_3X3,
+            PcfKernel::K5x5 => &GRID_5X5,
+            PcfKernel::Poisson => &POISSON_DISK,
+        }
+    }
+
+    /// 普通PCF时的采样半径(像素)
+    pub fn radius(self) -> f32 {
+        match self {
+            PcfKernel::K3x3 => 1.,
+            PcfKernel::K5x5 => 2.,
+            PcfKernel::Poisson => 2.,
+        }
+    }
+}
+
+const GRID_3X3: [(f32, f32); 9] = grid(1);
+const GRID_5X5: [(f32, f32); 25] = grid(2);
+
+// (2*half+1)^2 的网格，逐行排列，N必须等于网格的点数
+const fn grid<const N: usize>(half: i32) -> [(f32, f32); N] {
+    let side = 2 * half + 1;
+    let mut offsets = [(0., 0.); N];
+    let mut i = 0;
+    while i < N {
+        let (x, y) = (i as i32 % side - half, i as i32 / side - half);
+        offsets[i] = (x as f32 / half as f32, y as f32 / half as f32);
+        i += 1;
+    }
+    offsets
+}
+
+/// 阴影贴图的采样方式
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ShadowFilter {
+    /// 只采样一次，阴影边缘是硬的
+    #[default]
+    Hard,
+    /// Percentage-closer filtering，对周围多次深度比较取平均
+    Pcf(PcfKernel),
+    /// Percentage-closer soft shadows
+    ///
+    /// 先搜索遮挡物的平均深度，根据光源大小估计半影宽度，再用对应半径做PCF
+    Pcss {
+        kernel: PcfKernel,
+        /// 光源大小(阴影贴图像素)，越大半影越宽
+        light_size: f32,
+    },
+}
+
+/// 在阴影贴图上采样可见度，1表示被光照到，0表示完全在阴影中
+///
+/// (x,y) 片段在光源屏幕空间的坐标，depth 片段在光源空间的深度(已加上偏移)
+pub fn sample(filter: ShadowFilter, map: &DepthBuffer<f32>, x: f32, y: f32, depth: f32) -> f32 {
+    match filter {
+        ShadowFilter::Hard => lit(map, x, y, depth),
+        ShadowFilter::Pcf(kernel) => pcf(map, x, y, depth, kernel, kernel.radius()),
+        ShadowFilter::Pcss { kernel, light_size } => pcss(map, x, y, depth, kernel, light_size),
+    }
+}
+
+// 单次深度比较，超出阴影贴图范围的认为不在阴影中
+fn lit(map: &DepthBuffer<f32>, x: f32, y: f32, depth: f32) -> f32 {
+    if x < 0. || y < 0. || x >= map.width() as f32 || y >= map.height() as f32 {
+        return 1.;
+    }
+    if map.test(x as u32, y as u32, depth) {
+        1.
+    } else {
+        0.
+    }
+}
+
+fn pcf(map: &DepthBuffer<f32>, x: f32, y: f32, depth: f32, kernel: PcfKernel, radius: f32) -> f32 {
+    let offsets = kernel.offsets();
+    let sum: f32 = offsets
+        .iter()
+        .map(|(ox, oy)| lit(map, x + ox * radius, y + oy * radius, depth))
+        .sum();
+    sum / offsets.len() as f32
+}
+
+// 深度值转换成离光源的距离，深度越大越近时取反
+fn distance(map: &DepthBuffer<f32>, depth: f32) -> f32 {
+    match map.func() {
+        DepthFunc::Greater | DepthFunc::GreaterEqual => 1. - depth,
+        _ => depth,
+    }
+}
+
+fn pcss(
+    map: &DepthBuffer<f32>,
+    x: f32,
+    y: f32,
+    depth: f32,
+    kernel: PcfKernel,
+    light_size: f32,
+) -> f32 {
+    // 1. 在光源大小的范围内搜索遮挡物，求平均距离
+    let (mut blocker_sum, mut blockers) = (0., 0);
+    for &(ox, oy) in kernel.offsets() {
+        let (sx, sy) = (x + ox * light_size, y + oy * light_size);
+        if sx < 0. || sy < 0. || sx >= map.width() as f32 || sy >= map.height() as f32 {
+            continue;
+        }
+        if !map.test(sx as u32, sy as u32, depth) {
+            blocker_sum += distance(map, map.get(sx as u32, sy as u32));
+            blockers += 1;
+        }
+    }
+    if blockers == 0 {
+        return 1.;
+    }
+    let blocker = blocker_sum / blockers as f32;
+    let receiver = distance(map, depth);
+
+    // 2. 相似三角形估计半影宽度
+    let penumbra = (receiver - blocker).max(0.) * light_size / blocker.max(1e-4);
+
+    // 3. 用半影宽度做PCF，至少保留一个像素的过滤
+    pcf(map, x, y, depth, kernel, penumbra.max(1.))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_kernels_cover_unit_square() {
+        let k3 = PcfKernel::K3x3.offsets();
+        assert_eq!(k3.len(), 9);
+        assert_eq!((k3[0], k3[4], k3[8]), ((-1., -1.), (0., 0.), (1., 1.)));
+        assert_eq!(k3[5], (1., 0.));
+        let k5 = PcfKernel::K5x5.offsets();
+        assert_eq!(k5.len(), 25);
+        assert_eq!((k5[0], k5[12], k5[24]), ((-1., -1.), (0., 0.), (1., 1.)));
+        assert_eq!(k5[6], (-0.5, -0.5));
+        assert_eq!(PcfKernel::Poisson.offsets().len(), 16);
+    }
+}
@@ -0,0 +1,215 @@
+//! 超采样抗锯齿
+//!
+//! 按factor倍的分辨率渲染，输出时用重建滤波器缩小到目标尺寸
+//!
+//! 滤波器是可分离的，先横向再纵向缩小，滤波器半径以输出像素为单位
+//!
+//! 深度不能滤波: Lanczos和Mitchell的负权重会超出[0,1]，轮廓处还会和清除值混合，
+//! 所以每个输出像素取深度测试意义上最近的源像素
+
+use std::str::FromStr;
+
+use image::{ImageBuffer, Rgba};
+use num::Float;
+use serde::Deserialize;
+
+use crate::{error::RenderError, parse_name};
+
+use super::depth::DepthBuffer;
+
+/// 超采样倍数的上限
+pub const MAX_FACTOR: u32 = 16;
+
+/// 缩小时使用的滤波器
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownsampleFilter {
+    /// 输出像素覆盖范围内的平均值
+    #[default]
+    Box,
+    /// 三角形滤波，相邻像素各占一部分
+    Tent,
+    /// Lanczos3，更锐利，边缘可能有轻微振铃
+    Lanczos,
+    /// Mitchell-Netravali (B=C=1/3)，锐利和振铃之间的折中
+    Mitchell,
+}
+
+impl FromStr for DownsampleFilter {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+impl DownsampleFilter {
+    // 滤波器的半径，超出半径的权重为0
+    fn radius(self) -> f64 {
+        match self {
+            DownsampleFilter::Box => 0.5,
+            DownsampleFilter::Tent => 1.,
+            DownsampleFilter::Lanczos => 3.,
+            DownsampleFilter::Mitchell => 2.,
+        }
+    }
+
+    fn weight(self, x: f64) -> f64 {
+        let x = x.abs();
+        if x >= self.radius() {
+            return 0.;
+        }
+        match self {
+            DownsampleFilter::Box => 1.,
+            DownsampleFilter::Tent => 1. - x,
+            DownsampleFilter::Lanczos => sinc(x) * sinc(x / 3.),
+            DownsampleFilter::Mitchell => {
+                let (b, c) = (1. / 3., 1. / 3.);
+                let (x2, x3) = (x * x, x * x * x);
+                let w = if x < 1. {
+                    (12. - 9. * b - 6. * c) * x3 + (-18. + 12. * b + 6. * c) * x2 + (6. - 2. * b)
+                } else {
+                    (-b - 6. * c) * x3
+                        + (6. * b + 30. * c) * x2
+                        + (-12. * b - 48. * c) * x
+                        + (8. * b + 24. * c)
+                };
+                w / 6.
+            }
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0. {
+        return 1.;
+    }
+    let x = x * std::f64::consts::PI;
+    x.sin() / x
+}
+
+// 每个输出像素对应的源像素起点和归一化的权重
+fn weights(dst_len: u32, factor: u32, filter: DownsampleFilter) -> Vec<(usize, Vec<f64>)> {
+    let src_len = (dst_len * factor) as i64;
+    let f = factor as f64;
+    let support = (filter.radius() * f).ceil() as i64;
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f64 + 0.5) * f;
+            let start = (center as i64 - support).max(0);
+            let end = (center as i64 + support).min(src_len);
+            let mut w: Vec<f64> = (start..end)
+                .map(|j| filter.weight((j as f64 + 0.5 - center) / f))
+                .collect();
+            let sum: f64 = w.iter().sum();
+            w.iter_mut().for_each(|w| *w /= sum);
+            (start as usize, w)
+        })
+        .collect()
+}
+
+// 把w*h*channels的数据缩小factor倍
+fn downsample(
+    data: &[f64],
+    width: u32,
+    height: u32,
+    channels: usize,
+    factor: u32,
+    filter: DownsampleFilter,
+) -> Vec<f64> {
+    let (dw, dh) = (width / factor, height / factor);
+    let (src_w, src_h, dst_w) = (width as usize, height as usize, dw as usize);
+    // 横向
+    let mut tmp = vec![0.; dst_w * src_h * channels];
+    for (x, (start, ws)) in weights(dw, factor, filter).iter().enumerate() {
+        for y in 0..src_h {
+            for (k, wk) in ws.iter().enumerate() {
+                let src = ((start + k) + y * src_w) * channels;
+                let dst = (x + y * dst_w) * channels;
+                tmp[dst..dst + channels]
+                    .iter_mut()
+                    .zip(&data[src..src + channels])
+                    .for_each(|(t, d)| *t += d * wk);
+            }
+        }
+    }
+    // 纵向
+    let mut out = vec![0.; dst_w * dh as usize * channels];
+    for (y, (start, ws)) in weights(dh, factor, filter).iter().enumerate() {
+        for (k, wk) in ws.iter().enumerate() {
+            let src = (start + k) * dst_w * channels;
+            let dst = y * dst_w * channels;
+            let row = dst_w * channels;
+            out[dst..dst + row]
+                .iter_mut()
+                .zip(&tmp[src..src + row])
+                .for_each(|(o, t)| *o += t * wk);
+        }
+    }
+    out
+}
+
+/// 把颜色图像缩小factor倍，宽高需要是factor的整数倍
+pub fn downsample_color(
+    image: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    factor: u32,
+    filter: DownsampleFilter,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let data: Vec<f64> = image.as_raw().iter().map(|&c| c as f64).collect();
+    let out = downsample(&data, image.width(), image.height(), 4, factor, filter);
+    let raw = out
+        .iter()
+        .map(|c| c.round().clamp(0., 255.) as u8)
+        .collect();
+    ImageBuffer::from_raw(image.width() / factor, image.height() / factor, raw).unwrap()
+}
+
+/// 把深度缩小factor倍，深度测试设置保持不变
+///
+/// 每个输出像素取factor*factor个源像素中最能通过深度测试的一个，截断到[0,1]
+pub fn downsample_depth<D: Float>(depth: &DepthBuffer<D>, factor: u32) -> DepthBuffer<D> {
+    let (w, h) = (depth.width() / factor, depth.height() / factor);
+    let func = depth.func();
+    let mut result = DepthBuffer::new(w, h, func, depth.clear_value());
+    for y in 0..h {
+        for x in 0..w {
+            let mut nearest = depth.get(x * factor, y * factor);
+            for sy in y * factor..(y + 1) * factor {
+                for sx in x * factor..(x + 1) * factor {
+                    let d = depth.get(sx, sy);
+                    if func.compare(d, nearest) {
+                        nearest = d;
+                    }
+                }
+            }
+            result.set(x, y, nearest.max(D::zero()).min(D::one()));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::depth::DepthFunc;
+
+    #[test]
+    fn depth_keeps_nearest_sample() {
+        // 左边一块只有一个采样点被覆盖，右边一块全部覆盖
+        let mut depth = DepthBuffer::new(4, 2, DepthFunc::GreaterEqual, 0.);
+        depth.set(1, 1, 0.7);
+        for (x, y) in [(2, 0), (3, 0), (2, 1), (3, 1)] {
+            depth.set(x, y, 0.2 + 0.1 * x as f32);
+        }
+        let out = downsample_depth(&depth, 2);
+        assert_eq!((out.width(), out.height()), (2, 1));
+        assert_eq!(out.as_slice(), [0.7, 0.5]);
+
+        // 超出[0,1]的深度被截断
+        depth.set(0, 0, 1.5);
+        assert_eq!(downsample_depth(&depth, 2).as_slice(), [1., 0.5]);
+
+        depth.set_func(DepthFunc::Less);
+        assert_eq!(downsample_depth(&depth, 2).as_slice(), [0., 0.4]);
+    }
+}
@@ -0,0 +1,247 @@
+//! 屏幕空间环境光遮蔽(SSAO)
+//!
+//! 从深度缓冲重建每个像素的世界坐标，在法线方向的半球内随机取点，
+//! 投影回屏幕和深度缓冲比较，被挡住的点越多越暗，最后模糊掉随机旋转带来的噪点
+//!
+//! 结果是每个像素的遮蔽系数，1表示完全不被遮挡，着色时乘到环境光上
+
+use glm::{GenSquareMat, Mat4, Vec3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    any_perpendicular,
+    error::{RenderError, Result},
+    smoothstep, v4p2v3,
+};
+
+use super::{depth::DepthBuffer, target::Plane};
+
+// 随机旋转向量平铺的边长，模糊半径取它的一半正好消除平铺的图案
+const NOISE_SIZE: usize = 4;
+
+/// SSAO参数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ssao {
+    /// 采样半球的半径(世界坐标)
+    pub radius: f32,
+    /// 每个像素的采样点数
+    pub samples: u32,
+    /// 采样前沿法线偏移的距离(世界坐标)，防止平面自遮挡
+    pub bias: f32,
+    /// 模糊半径(像素)，0表示不模糊
+    pub blur: u32,
+    /// 随机数种子，同样的种子得到同样的结果
+    pub seed: u64,
+}
+
+impl Default for Ssao {
+    fn default() -> Self {
+        Self {
+            radius: 0.1,
+            samples: 16,
+            bias: 0.005,
+            blur: NOISE_SIZE as u32 / 2,
+            seed: 0,
+        }
+    }
+}
+
+impl Ssao {
+    /// 计算depth中每个像素的遮蔽系数，没有几何体的像素为1
+    ///
+    /// world_to_screen 把世界坐标变换到depth的屏幕坐标，一般是 view_port*projection*view，
+    /// normals是世界坐标的单位法线(比如 deferred::GBuffer::normal)，None时从深度重建
+    pub fn compute(
+        &self,
+        depth: &DepthBuffer<f32>,
+        normals: Option<&Plane<Vec3>>,
+        world_to_screen: Mat4,
+    ) -> Result<Plane<f32>> {
+        let screen_to_world = world_to_screen
+            .inverse()
+            .ok_or(RenderError::SingularMatrix("view_port*projection*view"))?;
+        let (width, height) = (depth.width(), depth.height());
+        let func = depth.func();
+        // 深度往哪个方向变化表示离摄像机更近
+        let nearer = if func.compare(1., 0.) { 1. } else { -1. };
+        let empty = |d: f32| d == depth.clear_value();
+        let position = |x: f32, y: f32, d: f32| v4p2v3(screen_to_world * glm::vec4(x, y, d, 1.));
+        let position_at = |x: u32, y: u32| {
+            let d = depth.get(x, y);
+            (!empty(d)).then(|| position(x as f32 + 0.5, y as f32 + 0.5, d))
+        };
+
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        // 半球内的采样点，越靠近中心越密
+        let samples = self.samples.max(1);
+        let kernel: Vec<Vec3> = (0..samples)
+            .map(|i| {
+                let v = glm::vec3(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(0.0..1.0),
+                );
+                let v = glm::normalize(v) * rng.gen_range(0.0..1.0);
+                let t = i as f32 / samples as f32;
+                v * (0.1 + 0.9 * t * t)
+            })
+            .collect();
+        // 平铺在屏幕上的随机旋转，绕法线旋转采样点
+        let noise: Vec<Vec3> = (0..NOISE_SIZE * NOISE_SIZE)
+            .map(|_| glm::vec3(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.))
+            .collect();
+
+        let mut occlusion = Plane::new(width, height, 1.);
+        for y in 0..height {
+            for x in 0..width {
+                let d = depth.get(x, y);
+                if empty(d) {
+                    continue;
+                }
+                let (sx, sy) = (x as f32 + 0.5, y as f32 + 0.5);
+                let p = position(sx, sy, d);
+                // 指向摄像机的方向，用来确定重建法线的朝向
+                let to_eye = position(sx, sy, d + nearer * 1e-3) - p;
+                let n = match normals {
+                    Some(normals) => normals.get(x, y),
+                    None => match reconstruct_normal(&position_at, x, y, width, height, p) {
+                        Some(n) if glm::dot(n, to_eye) < 0. => -n,
+                        Some(n) => n,
+                        None => continue,
+                    },
+                };
+
+                let r = noise[(x as usize % NOISE_SIZE) + (y as usize % NOISE_SIZE) * NOISE_SIZE];
+                let tangent = r - n * glm::dot(r, n);
+                let tangent = if glm::length(tangent) > 1e-4 {
+                    glm::normalize(tangent)
+                } else {
+                    any_perpendicular(n)
+                };
+                let bitangent = glm::cross(n, tangent);
+                let origin = p + n * self.bias;
+
+                let mut occluded = 0.;
+                for k in &kernel {
+                    let s = origin + (tangent * k.x + bitangent * k.y + n * k.z) * self.radius;
+                    let screen = v4p2v3(world_to_screen * s.extend(1.));
+                    let (qx, qy) = (screen.x.floor(), screen.y.floor());
+                    if qx < 0. || qy < 0. || qx >= width as f32 || qy >= height as f32 {
+                        continue;
+                    }
+                    let (qx, qy) = (qx as u32, qy as u32);
+                    let scene = depth.get(qx, qy);
+                    // 采样点被场景中更近的表面挡住
+                    if empty(scene) || !func.compare(scene, screen.z) {
+                        continue;
+                    }
+                    // 离得太远的遮挡物(比如背景前的轮廓)影响逐渐减弱
+                    let q = position(qx as f32 + 0.5, qy as f32 + 0.5, scene);
+                    let dist = glm::length(q - p).max(f32::MIN_POSITIVE);
+                    occluded += smoothstep(self.radius / dist);
+                }
+                occlusion.set(x, y, 1. - occluded / samples as f32);
+            }
+        }
+        Ok(self.blur(&occlusion, depth))
+    }
+
+    // 只在有几何体的像素间做盒式模糊
+    fn blur(&self, occlusion: &Plane<f32>, depth: &DepthBuffer<f32>) -> Plane<f32> {
+        if self.blur == 0 {
+            return occlusion.clone();
+        }
+        let (width, height) = (occlusion.width() as i32, occlusion.height() as i32);
+        let r = self.blur as i32;
+        let mut out = occlusion.clone();
+        for y in 0..height {
+            for x in 0..width {
+                if depth.get(x as u32, y as u32) == depth.clear_value() {
+                    continue;
+                }
+                let (mut sum, mut count) = (0., 0);
+                for qy in (y - r).max(0)..=(y + r).min(height - 1) {
+                    for qx in (x - r).max(0)..=(x + r).min(width - 1) {
+                        if depth.get(qx as u32, qy as u32) != depth.clear_value() {
+                            sum += occlusion.get(qx as u32, qy as u32);
+                            count += 1;
+                        }
+                    }
+                }
+                out.set(x as u32, y as u32, sum / count as f32);
+            }
+        }
+        out
+    }
+}
+
+// 用相邻像素的位置差重建法线，每个方向取深度变化小的一侧，避免跨过轮廓
+fn reconstruct_normal(
+    position_at: &impl Fn(u32, u32) -> Option<Vec3>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    p: Vec3,
+) -> Option<Vec3> {
+    let closest = |a: Option<Vec3>, b: Option<Vec3>| match (a, b) {
+        (Some(a), Some(b)) if glm::length(b - p) < glm::length(p - a) => Some(b - p),
+        (Some(a), _) => Some(p - a),
+        (None, Some(b)) => Some(b - p),
+        (None, None) => None,
+    };
+    let dx = closest(
+        (x > 0).then(|| position_at(x - 1, y)).flatten(),
+        (x + 1 < width).then(|| position_at(x + 1, y)).flatten(),
+    )?;
+    let dy = closest(
+        (y > 0).then(|| position_at(x, y - 1)).flatten(),
+        (y + 1 < height).then(|| position_at(x, y + 1)).flatten(),
+    )?;
+    let n = glm::cross(dx, dy);
+    (glm::length(n) > 0.).then(|| glm::normalize(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::{depth::DepthFunc, viewport};
+
+    const SIZE: u32 = 64;
+
+    // 和Renderer默认设置一致: 投影为单位矩阵，深度越大越近，世界坐标z=0映射到深度0.5
+    fn render(z: impl Fn(f32) -> f32) -> (DepthBuffer<f32>, Mat4) {
+        let world_to_screen = viewport(0, 0, SIZE as i32, SIZE as i32);
+        let mut depth = DepthBuffer::new(SIZE, SIZE, DepthFunc::GreaterEqual, 0.);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let wx = (x as f32 + 0.5) / SIZE as f32 * 2. - 1.;
+                depth.set(x, y, (z(wx) + 1.) / 2.);
+            }
+        }
+        (depth, world_to_screen)
+    }
+
+    #[test]
+    fn flat_plane_is_not_occluded() {
+        let (depth, world_to_screen) = render(|_| 0.);
+        let ao = Ssao::default()
+            .compute(&depth, None, world_to_screen)
+            .unwrap();
+        for &ao in ao.as_slice() {
+            assert!(ao > 0.99, "{}", ao);
+        }
+    }
+
+    #[test]
+    fn concave_crease_is_occluded() {
+        // 平台中间一条V形的沟，沟底离摄像机最远
+        let (depth, world_to_screen) = render(|x| (2. * x.abs()).min(0.5));
+        let ao = Ssao::default()
+            .compute(&depth, None, world_to_screen)
+            .unwrap();
+        let (crease, side) = (ao.get(SIZE / 2, SIZE / 2), ao.get(SIZE / 8, SIZE / 2));
+        assert!(crease < 0.9, "{}", crease);
+        assert!(side > 0.99, "{}", side);
+    }
+}
@@ -0,0 +1,113 @@
+//! 渲染目标
+//!
+//! 片段着色器的输出由光栅化写入渲染目标，普通的颜色图像是一个目标，
+//! G-buffer是一组缓冲，一次写入多个输出
+
+use image::{imageops, GenericImageView, ImageBuffer, Pixel};
+
+/// 光栅化的输出目标
+pub trait RenderTarget: Sized {
+    /// 片段着色器输出的一个像素
+    type Value: Copy;
+
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn put(&mut self, x: u32, y: u32, value: Self::Value);
+    /// 复制出(x,y)开始的w*h区域，分块并行绘制时每个块使用自己的副本
+    fn region(&self, x: u32, y: u32, w: u32, h: u32) -> Self;
+    /// 把src写回到(x,y)开始的区域，和region配合使用
+    fn copy_from(&mut self, src: &Self, x: u32, y: u32);
+}
+
+impl<P: Pixel + 'static> RenderTarget for ImageBuffer<P, Vec<P::Subpixel>> {
+    type Value = P;
+
+    fn width(&self) -> u32 {
+        ImageBuffer::width(self)
+    }
+
+    fn height(&self) -> u32 {
+        ImageBuffer::height(self)
+    }
+
+    fn put(&mut self, x: u32, y: u32, value: P) {
+        self.put_pixel(x, y, value);
+    }
+
+    fn region(&self, x: u32, y: u32, w: u32, h: u32) -> Self {
+        self.view(x, y, w, h).to_image()
+    }
+
+    fn copy_from(&mut self, src: &Self, x: u32, y: u32) {
+        imageops::replace(self, src, x, y);
+    }
+}
+
+/// 按行存储的二维缓冲，G-buffer的每个输出使用一个
+#[derive(Debug, Clone)]
+pub struct Plane<T> {
+    width: u32,
+    height: u32,
+    data: Vec<T>,
+}
+
+impl<T: Copy> Plane<T> {
+    pub fn new(width: u32, height: u32, value: T) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![value; (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (x + y * self.width) as usize
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> T {
+        self.data[self.index(x, y)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, value: T) {
+        let i = self.index(x, y);
+        self.data[i] = value;
+    }
+
+    pub fn fill(&mut self, value: T) {
+        self.data.iter_mut().for_each(|v| *v = value);
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn region(&self, x: u32, y: u32, w: u32, h: u32) -> Self {
+        let mut data = Vec::with_capacity((w * h) as usize);
+        for row in y..y + h {
+            let start = self.index(x, row);
+            data.extend_from_slice(&self.data[start..start + w as usize]);
+        }
+        Self {
+            width: w,
+            height: h,
+            data,
+        }
+    }
+
+    pub fn copy_from(&mut self, src: &Self, x: u32, y: u32) {
+        let w = src.width as usize;
+        for row in 0..src.height {
+            let start = self.index(x, y + row);
+            let src_start = (row * src.width) as usize;
+            self.data[start..start + w].copy_from_slice(&src.data[src_start..src_start + w]);
+        }
+    }
+}
@@ -0,0 +1,417 @@
+//! 纹理采样
+//!
+//! uv原点在左下角(贴图加载时已经上下翻转)，[0,1]覆盖整张贴图，
+//! 超出范围的坐标按环绕方式处理，不会越界
+//!
+//! 贴图加载时生成mipmap，片段着色器用uv在屏幕空间的导数选择层级
+
+use std::str::FromStr;
+
+use image::{ImageBuffer, Rgba};
+use serde::Deserialize;
+
+use crate::{error::RenderError, parse_name};
+
+type Image = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+/// 纹理过滤方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    /// 取最近的纹素
+    Nearest,
+    /// 相邻四个纹素双线性插值
+    #[default]
+    Bilinear,
+}
+
+impl FromStr for FilterMode {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+/// mip层级之间的过滤方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MipmapMode {
+    /// 只用第0层
+    None,
+    /// 取最近的层级
+    Nearest,
+    /// 相邻两层插值，配合双线性过滤就是三线性过滤
+    #[default]
+    Linear,
+}
+
+impl FromStr for MipmapMode {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+/// uv超出[0,1]时的环绕方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WrapMode {
+    /// 平铺
+    #[default]
+    Repeat,
+    /// 取边缘的纹素
+    Clamp,
+    /// 镜像平铺
+    Mirror,
+}
+
+impl FromStr for WrapMode {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_name(s)
+    }
+}
+
+impl WrapMode {
+    // 把纹素坐标映射到[0,size)
+    fn wrap(self, i: i32, size: i32) -> i32 {
+        match self {
+            WrapMode::Repeat => i.rem_euclid(size),
+            WrapMode::Clamp => i.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * size);
+                if i < size {
+                    i
+                } else {
+                    2 * size - 1 - i
+                }
+            }
+        }
+    }
+}
+
+/// 采样器状态，默认三线性过滤、平铺、不开启各向异性过滤
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sampler {
+    pub filter: FilterMode,
+    pub mipmap: MipmapMode,
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    /// 各向异性过滤沿长轴的最大采样次数，1表示关闭
+    pub max_anisotropy: u32,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self {
+            filter: FilterMode::default(),
+            mipmap: MipmapMode::default(),
+            wrap_u: WrapMode::default(),
+            wrap_v: WrapMode::default(),
+            max_anisotropy: 1,
+        }
+    }
+}
+
+impl Sampler {
+    /// u和v使用相同的环绕方式
+    pub fn new(filter: FilterMode, wrap: WrapMode) -> Self {
+        Self {
+            filter,
+            wrap_u: wrap,
+            wrap_v: wrap,
+            ..Default::default()
+        }
+    }
+}
+
+/// 带mipmap的贴图，第0层是原图，每层宽高减半直到1x1
+#[derive(Debug, Clone)]
+pub struct Texture {
+    levels: Vec<Image>,
+}
+
+impl Texture {
+    /// 用2x2的盒式滤波生成完整的mip链
+    pub fn new(image: Image) -> Self {
+        let mut levels = vec![image];
+        loop {
+            let prev = &levels[levels.len() - 1];
+            let (w, h) = (prev.width(), prev.height());
+            if w <= 1 && h <= 1 {
+                break;
+            }
+            let next = ImageBuffer::from_fn((w / 2).max(1), (h / 2).max(1), |x, y| {
+                let mut sum = [0u32; 4];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    // 奇数尺寸时最后一行或一列重复使用
+                    let p = prev.get_pixel((x * 2 + dx).min(w - 1), (y * 2 + dy).min(h - 1));
+                    sum.iter_mut()
+                        .zip(p.0)
+                        .for_each(|(sum, c)| *sum += c as u32);
+                }
+                Rgba(sum.map(|c| ((c + 2) / 4) as u8))
+            });
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// 不生成mipmap，只有第0层
+    pub fn without_mipmaps(image: Image) -> Self {
+        Self {
+            levels: vec![image],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.levels[0].width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.levels[0].height()
+    }
+
+    /// mip层级数
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// 第level层，超出时返回最后一层
+    pub fn level(&self, level: usize) -> &Image {
+        &self.levels[level.min(self.levels.len() - 1)]
+    }
+}
+
+impl From<Image> for Texture {
+    fn from(image: Image) -> Self {
+        Texture::new(image)
+    }
+}
+
+/// 贴图和采样器的组合
+#[derive(Debug, Clone, Copy)]
+pub struct Texture2D<'a> {
+    texture: &'a Texture,
+    sampler: Sampler,
+}
+
+impl<'a> Texture2D<'a> {
+    pub fn new(texture: &'a Texture, sampler: Sampler) -> Self {
+        Self { texture, sampler }
+    }
+
+    pub fn texture(&self) -> &'a Texture {
+        self.texture
+    }
+
+    pub fn sampler(&self) -> Sampler {
+        self.sampler
+    }
+
+    pub fn set_sampler(&mut self, sampler: Sampler) {
+        self.sampler = sampler;
+    }
+
+    /// 读取第level层的纹素，坐标按环绕方式处理，返回[0,1]的颜色
+    pub fn fetch(&self, level: usize, x: i32, y: i32) -> glm::Vec4 {
+        let image = self.texture.level(level);
+        let (w, h) = (image.width() as i32, image.height() as i32);
+        let x = self.sampler.wrap_u.wrap(x, w);
+        let y = self.sampler.wrap_v.wrap(y, h);
+        let p = image.get_pixel(x as u32, y as u32);
+        glm::vec4(p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32) / 255.
+    }
+
+    /// 在第0层采样，返回[0,1]的颜色
+    pub fn sample(&self, uv: glm::Vec2) -> glm::Vec4 {
+        self.sample_in_level(0, uv)
+    }
+
+    /// 按uv在屏幕空间的导数选择mip层级采样，类似GLSL的textureGrad
+    ///
+    /// 开启各向异性过滤时沿导数较长的方向多次采样，层级按短的方向选择
+    pub fn sample_grad(&self, uv: glm::Vec2, duv_dx: glm::Vec2, duv_dy: glm::Vec2) -> glm::Vec4 {
+        let size = glm::vec2(self.texture.width() as f32, self.texture.height() as f32);
+        let (lx, ly) = (glm::length(duv_dx * size), glm::length(duv_dy * size));
+        let (major, p_max, p_min) = if lx >= ly {
+            (duv_dx, lx, ly)
+        } else {
+            (duv_dy, ly, lx)
+        };
+        let max_anisotropy = self.sampler.max_anisotropy.max(1) as f32;
+        let n = (p_max / p_min.max(f32::MIN_POSITIVE))
+            .ceil()
+            .clamp(1., max_anisotropy);
+        let lod = (p_max / n).log2();
+        // 导数是NaN时n也是NaN，和不开启各向异性过滤一样处理
+        if n.is_nan() || n <= 1. {
+            return self.sample_level(uv, lod);
+        }
+        // 沿长轴在覆盖范围内均匀取n个点
+        let mut sum = glm::vec4(0., 0., 0., 0.);
+        for i in 0..n as u32 {
+            let t = (i as f32 + 0.5) / n - 0.5;
+            sum = sum + self.sample_level(uv + major * t, lod);
+        }
+        sum / n
+    }
+
+    /// 在指定的mip层级采样，lod可以是小数，类似GLSL的textureLod
+    pub fn sample_level(&self, uv: glm::Vec2, lod: f32) -> glm::Vec4 {
+        // 导数为0或NaN时使用第0层
+        let top = (self.texture.levels() - 1) as f32;
+        let lod = if lod > 0. { lod.min(top) } else { 0. };
+        match self.sampler.mipmap {
+            MipmapMode::None => self.sample_in_level(0, uv),
+            MipmapMode::Nearest => self.sample_in_level(lod.round() as usize, uv),
+            MipmapMode::Linear => {
+                let level = lod.floor();
+                let t = lod - level;
+                let a = self.sample_in_level(level as usize, uv);
+                if t == 0. {
+                    return a;
+                }
+                let b = self.sample_in_level(level as usize + 1, uv);
+                a + (b - a) * t
+            }
+        }
+    }
+
+    fn sample_in_level(&self, level: usize, uv: glm::Vec2) -> glm::Vec4 {
+        let image = self.texture.level(level);
+        let (w, h) = (image.width() as f32, image.height() as f32);
+        match self.sampler.filter {
+            FilterMode::Nearest => {
+                self.fetch(level, (uv.x * w).floor() as i32, (uv.y * h).floor() as i32)
+            }
+            FilterMode::Bilinear => {
+                // 纹素中心在(i+0.5)/size
+                let (x, y) = (uv.x * w - 0.5, uv.y * h - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i32, y0 as i32);
+                let (x1, y1) = (x0.saturating_add(1), y0.saturating_add(1));
+                let lerp = |a: glm::Vec4, b: glm::Vec4, t: f32| a + (b - a) * t;
+                let bottom = lerp(self.fetch(level, x0, y0), self.fetch(level, x1, y0), fx);
+                let top = lerp(self.fetch(level, x0, y1), self.fetch(level, x1, y1), fx);
+                lerp(bottom, top, fy)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4x4的贴图，红色通道是x*60，绿色通道是y*60
+    fn grid() -> Texture {
+        Texture::new(ImageBuffer::from_fn(4, 4, |x, y| {
+            Rgba([(x * 60) as u8, (y * 60) as u8, 0, 255])
+        }))
+    }
+
+    fn texel(x: f32, y: f32) -> glm::Vec4 {
+        glm::vec4(x * 60., y * 60., 0., 255.) / 255.
+    }
+
+    fn assert_near(actual: glm::Vec4, expected: glm::Vec4) {
+        let d = actual - expected;
+        assert!(
+            d.x.abs() < 1e-5 && d.y.abs() < 1e-5 && d.z.abs() < 1e-5 && d.w.abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn wrap_maps_into_range() {
+        let size = 4;
+        let cases = [
+            (WrapMode::Repeat, [3, 0, 3, 0, 0]),
+            (WrapMode::Clamp, [0, 0, 3, 3, 3]),
+            (WrapMode::Mirror, [0, 0, 3, 3, 0]),
+        ];
+        for (mode, expected) in cases {
+            for (i, expected) in [-1, 0, size - 1, size, 2 * size].into_iter().zip(expected) {
+                assert_eq!(mode.wrap(i, size), expected, "{:?} {}", mode, i);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_sample_outside_unit_square() {
+        let texture = grid();
+        // uv=1正好落在最后一个纹素之外，uv=(-0.25,1.5)落在(-1,6)
+        let cases = [
+            (WrapMode::Repeat, (0., 0.), (3., 2.)),
+            (WrapMode::Clamp, (3., 3.), (0., 3.)),
+            (WrapMode::Mirror, (3., 3.), (0., 1.)),
+        ];
+        for (wrap, one, outside) in cases {
+            let t = Texture2D::new(&texture, Sampler::new(FilterMode::Nearest, wrap));
+            assert_near(t.sample(glm::vec2(1., 1.)), texel(one.0, one.1));
+            assert_near(t.sample(glm::vec2(-0.25, 1.5)), texel(outside.0, outside.1));
+        }
+    }
+
+    #[test]
+    fn bilinear_sample_outside_unit_square() {
+        let texture = grid();
+        // uv=1在纹素坐标3.5，相邻的是3和4；uv=(-0.25,1.5)在(-1.5,5.5)，相邻的是-2,-1和5,6，权重都是一半
+        let cases = [
+            (WrapMode::Repeat, ((3., 0.), (3., 0.)), ((2., 3.), (1., 2.))),
+            (WrapMode::Clamp, ((3., 3.), (3., 3.)), ((0., 0.), (3., 3.))),
+            (WrapMode::Mirror, ((3., 3.), (3., 3.)), ((1., 0.), (2., 1.))),
+        ];
+        for (wrap, one, outside) in cases {
+            let t = Texture2D::new(&texture, Sampler::new(FilterMode::Bilinear, wrap));
+            let mid = |(x, y): ((f32, f32), (f32, f32))| texel((x.0 + x.1) / 2., (y.0 + y.1) / 2.);
+            assert_near(t.sample(glm::vec2(1., 1.)), mid(one));
+            assert_near(t.sample(glm::vec2(-0.25, 1.5)), mid(outside));
+        }
+    }
+
+    #[test]
+    fn mip_chain_halves_odd_and_non_square_sizes() {
+        let sizes = |w, h| {
+            let texture = Texture::new(ImageBuffer::new(w, h));
+            (0..texture.levels())
+                .map(|i| texture.level(i).dimensions())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sizes(5, 3), [(5, 3), (2, 1), (1, 1)]);
+        assert_eq!(sizes(8, 2), [(8, 2), (4, 1), (2, 1), (1, 1)]);
+        assert_eq!(sizes(1, 7), [(1, 7), (1, 3), (1, 1)]);
+        assert_eq!(sizes(1, 1), [(1, 1)]);
+    }
+
+    #[test]
+    fn lod_follows_derivatives() {
+        // 红色通道 0,80,160,240，第1层是40,200，最后一层是120
+        let texture = Texture::new(ImageBuffer::from_fn(4, 4, |x, _| {
+            Rgba([(x * 80) as u8, 0, 0, 255])
+        }));
+        let mut sampler = Sampler::new(FilterMode::Nearest, WrapMode::Clamp);
+        for max_anisotropy in [1, 8] {
+            sampler.max_anisotropy = max_anisotropy;
+            let t = Texture2D::new(&texture, sampler);
+            let uv = glm::vec2(0.1, 0.5);
+            let red = |d: glm::Vec2| (t.sample_grad(uv, d, d).x * 255.).round();
+            assert_eq!(red(glm::vec2(0., 0.)), 0.);
+            assert_eq!(red(glm::vec2(f32::NAN, f32::NAN)), 0.);
+            assert_eq!(red(glm::vec2(0.5, 0.)), 40.);
+            assert_eq!(red(glm::vec2(1e6, 1e6)), 120.);
+
+            let red = |lod: f32| (t.sample_level(uv, lod).x * 255.).round();
+            assert_eq!(red(f32::NAN), 0.);
+            assert_eq!(red(f32::NEG_INFINITY), 0.);
+            assert_eq!(red(1e6), 120.);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! 分块并行光栅化
+//!
+//! 先把三角形按屏幕包围盒分到 TILE_SIZE*TILE_SIZE 的块里，再由多个线程并行绘制各个块
+//!
+//! 着色器在线程间共享，块内的三角形按提交顺序绘制，
+//! 每个像素经历的深度测试和写入顺序和串行完全相同，所以结果一致
+
+use std::{
+    panic,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use num::Float;
+
+use crate::v4p2v3;
+
+use super::{
+    clip::{self, ClipVertex},
+    depth::DepthBuffer,
+    edge,
+    msaa::SampleCount,
+    our_gl::IShader,
+    rasterize_polygon,
+    target::RenderTarget,
+    RasterizerState, Region,
+};
+
+/// 块的边长(像素)
+pub const TILE_SIZE: u32 = 64;
+
+/// 用threads个线程绘制indices组成的三角形，效果等同于对每个面依次调用 triangle_with_shader
+///
+/// vertices是每个顶点的顶点着色器输出，indices每三个一组组成一个面
+#[allow(clippy::too_many_arguments)]
+pub fn draw_tiled<T, S, D>(
+    vertices: &[(glm::Vec4, S::Varyings)],
+    indices: &[u32],
+    view_port: &glm::Mat4,
+    state: &RasterizerState,
+    samples: SampleCount,
+    threads: usize,
+    shader: &S,
+    target: &mut T,
+    zbuffer: &mut DepthBuffer<D>,
+) where
+    T: RenderTarget<Value = S::Output> + Send + Sync,
+    S: IShader + Sync,
+    S::Varyings: Send + Sync,
+    D: Float + From<f32> + Send + Sync,
+{
+    let n = samples.count();
+    let (width, height) = (target.width() / n, target.height());
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let tiles_y = height.div_ceil(TILE_SIZE);
+
+    // 分块: 裁剪每个三角形，记录没被剔除的三角形的包围盒覆盖了哪些块
+    let face_count = indices.len() / 3;
+    let mut polygons: Vec<(Vec<ClipVertex>, [S::Varyings; 3])> = Vec::with_capacity(face_count);
+    let mut bins: Vec<Vec<usize>> = vec![Vec::new(); (tiles_x * tiles_y) as usize];
+    for (i, face) in indices.chunks_exact(3).enumerate() {
+        let tri = [0, 1, 2].map(|j| vertices[face[j] as usize]);
+        let polygon = clip::clip_triangle(tri.map(|v| v.0));
+        let screen: Vec<glm::Vec3> = polygon.iter().map(|v| v4p2v3(*view_port * v.pos)).collect();
+        // 和光栅化时一样逐个扇形三角形判断，只有全部被剔除才跳过
+        let visible = (1..screen.len().saturating_sub(1))
+            .any(|k| !state.culls(edge::signed_area([screen[0], screen[k], screen[k + 1]])));
+        if visible {
+            let (mut min, mut max) = (glm::vec2(f32::MAX, f32::MAX), glm::vec2(f32::MIN, f32::MIN));
+            for p in &screen {
+                min = glm::vec2(min.x.min(p.x), min.y.min(p.y));
+                max = glm::vec2(max.x.max(p.x), max.y.max(p.y));
+            }
+            // 覆盖范围不小于光栅化时按像素中心取的包围盒
+            let tile = |v: f32, size: u32| (v.clamp(0., size as f32 - 1.) as u32) / TILE_SIZE;
+            for ty in tile(min.y, height)..=tile(max.y, height) {
+                for tx in tile(min.x, width)..=tile(max.x, width) {
+                    bins[(tx + ty * tiles_x) as usize].push(i);
+                }
+            }
+        }
+        polygons.push((polygon, tri.map(|v| v.1)));
+    }
+
+    let jobs: Vec<usize> = (0..bins.len()).filter(|&t| !bins[t].is_empty()).collect();
+    let threads = threads.clamp(1, jobs.len().max(1));
+    let next = AtomicUsize::new(0);
+
+    // 每个块复制出自己的渲染目标和深度，画完再写回
+    let tiles: Vec<(Region, T, DepthBuffer<D>)> = {
+        let (jobs, bins, polygons, next) = (&jobs, &bins, &polygons, &next);
+        let (target, zbuffer) = (&*target, &*zbuffer);
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    s.spawn(move || {
+                        let mut done = Vec::new();
+                        loop {
+                            let job = next.fetch_add(1, Ordering::Relaxed);
+                            let t = match jobs.get(job) {
+                                Some(&t) => t as u32,
+                                None => break,
+                            };
+                            let (x, y) = ((t % tiles_x) * TILE_SIZE, (t / tiles_x) * TILE_SIZE);
+                            let (w, h) = (TILE_SIZE.min(width - x), TILE_SIZE.min(height - y));
+                            let region = Region {
+                                x: x as i32,
+                                y: y as i32,
+                                w: w as i32,
+                                h: h as i32,
+                            };
+                            let mut tile_target = target.region(x * n, y, w * n, h);
+                            let mut tile_depth = zbuffer.region(x * n, y, w * n, h);
+                            for &i in &bins[t as usize] {
+                                let (polygon, varyings) = &polygons[i];
+                                rasterize_polygon(
+                                    polygon,
+                                    varyings,
+                                    view_port,
+                                    state,
+                                    samples,
+                                    region,
+                                    shader,
+                                    &mut tile_target,
+                                    &mut tile_depth,
+                                );
+                            }
+                            done.push((region, tile_target, tile_depth));
+                        }
+                        done
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        })
+    };
+
+    for (region, tile_target, tile_depth) in tiles {
+        let (x, y) = (region.x as u32 * n, region.y as u32);
+        target.copy_from(&tile_target, x, y);
+        zbuffer.copy_from(&tile_depth, x, y);
+    }
+}
@@ -0,0 +1,94 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+
+/// 加载资源和渲染时的错误
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("file `{0}` not found")]
+    MissingFile(PathBuf),
+    #[error("failed to read `{path}`")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("bad OBJ data in `{path}`")]
+    Obj {
+        path: PathBuf,
+        #[source]
+        source: obj::ObjError,
+    },
+    #[error("bad MTL data in `{path}` at line {line}: {message}")]
+    Mtl {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    #[error("failed to decode texture `{path}`")]
+    Texture {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+    #[error("failed to save image `{path}`")]
+    Save {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+    #[error("{0} matrix is not invertible")]
+    SingularMatrix(&'static str),
+    #[error("index {index} is out of range for a mesh with {vertices} vertices")]
+    IndexOutOfRange { index: usize, vertices: usize },
+    #[error("index buffer length {0} is not a multiple of 3")]
+    IncompleteTriangle(usize),
+    #[error("material `{material}` of `{model}` has no {kind} map")]
+    MissingTexture {
+        model: PathBuf,
+        material: String,
+        kind: &'static str,
+    },
+    #[error("render target is {found:?}, expected {expected:?}")]
+    TargetSize {
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    #[error("supersampling factor {0} is out of range for the render size")]
+    SupersamplingFactor(u32),
+    #[error("{0} does not support multisampling")]
+    MultisampleUnsupported(&'static str),
+    #[error(transparent)]
+    Name(#[from] serde::de::value::Error),
+    #[error("{shader} shader supports at most {max} lights, found {found}")]
+    TooManyLights {
+        shader: &'static str,
+        found: usize,
+        max: usize,
+    },
+    #[error("bad scene `{path}`: {message}")]
+    Scene { path: PathBuf, message: String },
+}
+
+pub type Result<T, E = RenderError> = std::result::Result<T, E>;
+
+impl RenderError {
+    /// 文件不存在时单独归类，方便上层给出提示
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        let path = path.into();
+        match source.kind() {
+            io::ErrorKind::NotFound => RenderError::MissingFile(path),
+            _ => RenderError::Io { path, source },
+        }
+    }
+
+    pub fn texture(path: impl Into<PathBuf>, source: image::ImageError) -> Self {
+        match source {
+            image::ImageError::IoError(e) => RenderError::io(path, e),
+            source => RenderError::Texture {
+                path: path.into(),
+                source,
+            },
+        }
+    }
+}
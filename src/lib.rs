@@ -0,0 +1,84 @@
+use image::Rgba;
+use serde::de::{value::StrDeserializer, DeserializeOwned, IntoDeserializer};
+
+pub mod draw;
+pub mod error;
+pub mod material;
+pub mod model;
+pub mod renderer;
+pub mod scene;
+
+pub use draw::our_gl;
+pub use error::RenderError;
+pub use our_gl::{
+    shader_impl_gbuffer_shader::GBufferShader,
+    shader_impl_gouraud_shader::GouraudShader,
+    shader_impl_pbr_shader::{PbrMaterial, PbrShader},
+    shader_impl_phong_shader::PhongShader,
+    shader_impl_shadow_shader::ShadowShader,
+    Fragment, IShader, Interpolate, SurfaceVaryings,
+};
+pub use renderer::Renderer;
+
+pub const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+pub const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+pub const GREEN: Rgba<u8> = Rgba([0, 255, 0, 255]);
+pub const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+pub const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// 齐次坐标系中的点投影到3d
+/// 点坐标需要除以w
+pub fn v4p2v3(v: glm::Vec4) -> glm::Vec3 {
+    glm::vec3(v.x / v.w, v.y / v.w, v.z / v.w)
+}
+
+/// 齐次坐标系中的向量投影到3d
+/// 向量坐标不需要除以w
+pub fn vec4_to_3(v: glm::Vec4) -> glm::Vec3 {
+    glm::vec3(v.x, v.y, v.z)
+}
+
+/// 任意一个和n垂直的单位向量，n是零向量时返回一个坐标轴
+pub fn any_perpendicular(n: glm::Vec3) -> glm::Vec3 {
+    // 选一个和n夹角足够大的坐标轴做叉乘
+    let axis = if n.x.abs() < 0.9 * glm::length(n) {
+        glm::vec3(1., 0., 0.)
+    } else {
+        glm::vec3(0., 1., 0.)
+    };
+    let p = glm::cross(n, axis);
+    let len = glm::length(p);
+    if len > 0. {
+        p / len
+    } else {
+        axis
+    }
+}
+
+/// 一组点的包围盒的外接球(center, radius)，没有点时返回None
+///
+/// 半径稍微放大，边缘上的点不会被视锥体裁掉
+pub fn bounding_sphere<I: IntoIterator<Item = glm::Vec3>>(points: I) -> Option<(glm::Vec3, f32)> {
+    let mut points = points.into_iter();
+    let first = points.next()?;
+    let (min, max) = points.fold((first, first), |(min, max), p| {
+        (
+            glm::vec3(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+            glm::vec3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+        )
+    });
+    let radius = (glm::length(max - min) * 0.5).max(f32::EPSILON) * 1.01;
+    Some(((min + max) * 0.5, radius))
+}
+
+/// 按serde的名字把字符串解析成枚举，命令行和场景文件用同一套名字
+pub fn parse_name<T: DeserializeOwned>(s: &str) -> Result<T, RenderError> {
+    let de: StrDeserializer<'_, serde::de::value::Error> = s.into_deserializer();
+    Ok(T::deserialize(de)?)
+}
+
+/// 三次Hermite平滑过渡，t先截断到[0,1]
+pub fn smoothstep(t: f32) -> f32 {
+    let t = t.clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
@@ -1,109 +1,271 @@
-#![allow(unused_variables)]
-#![allow(dead_code)]
-use std::{fs::File, io::BufReader};
-
-use draw::{
-    lookat,
-    our_gl::{
-        shader_impl_gouraud_shader::GouraudShader, shader_impl_phong_shader::PhongShader, IShader,
+use anyhow::{Context, Result};
+use cli::{Args, NormalSpace, ShaderKind, USAGE};
+use num::One;
+use tinyrenderer::{
+    bounding_sphere,
+    draw::{
+        ao_bake::{self, AoBake},
+        deferred::{self, GBuffer},
+        light::Light,
+        lookat,
+        shadow::{ShadowMap, ShadowedLights},
+        shadow_filter::{PcfKernel, ShadowFilter},
+        ssao::Ssao,
+        viewport, RasterizerState,
     },
-    triangle_with_shader, viewport,
+    model::{load_texture, DrawBatch, Model, Texture},
+    scene::Scene,
+    GBufferShader, GouraudShader, PbrMaterial, PbrShader, PhongShader, Renderer, ShadowShader,
 };
-use image::{imageops::flip_vertical_in_place, ImageBuffer, Luma, Rgba};
-use num::{One, Zero};
 
-use crate::draw::our_gl::shader_impl_shadow_shader::ShadowShader;
+mod cli;
 
-mod draw;
-
-const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
-const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
-const GREEN: Rgba<u8> = Rgba([0, 255, 0, 255]);
-const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
-const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
-
-/// 齐次坐标系中的点投影到3d
-/// 点坐标需要除以w
-pub fn v4p2v3(v: glm::Vec4) -> glm::Vec3 {
-    glm::vec3(v.x / v.w, v.y / v.w, v.z / v.w)
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
 }
 
-/// 齐次坐标系中的向量投影到3d
-/// 向量坐标不需要除以w
-pub fn vec4_to_3(v: glm::Vec4) -> glm::Vec3 {
-    glm::vec3(v.x, v.y, v.z)
-}
+fn run() -> Result<()> {
+    let args = match Args::parse(std::env::args().skip(1))? {
+        Some(args) => args,
+        None => {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+    };
+    if let Some(scene) = &args.scene {
+        Scene::load(scene)?.render()?;
+        return Ok(());
+    }
+    let light_dir = glm::normalize(args.light_dir);
+    let lights = [Light::directional(light_dir)];
+    let (width, height) = (args.width, args.height);
 
-fn main() {
-    let eye = glm::vec3(1., 1., 3.); // camera
-    let center = glm::vec3(0., 0., 0.);
-    let up = glm::vec3(0., 1., 0.);
-    // let light_dir = glm::normalize(glm::vec3(1., 1., 0.9));
-    let light_dir = glm::normalize(glm::vec3(1., 1., 0.));
-    let (width, height) = (800, 800);
-    let mut diffus = image::open("obj/african_head/african_head_diffuse.tga")
-        .unwrap()
-        .to_rgba8();
-    let mut diffus_nm = image::open("obj/african_head/african_head_nm.tga")
-        .unwrap()
-        .to_rgba8();
-    let mut diffus_spec = image::open("obj/african_head/african_head_spec.tga")
-        .unwrap()
-        .to_rgba8();
-    let _ = flip_vertical_in_place(&mut diffus);
-    let _ = flip_vertical_in_place(&mut diffus_nm);
-    let _ = flip_vertical_in_place(&mut diffus_spec);
-    let mut image = ImageBuffer::<Rgba<u8>, _>::from_pixel(width, height, BLACK);
-    let mut zbuffer = ImageBuffer::<Luma<u8>, _>::from_pixel(width, height, Luma([0]));
-    //let mut zbuffer = vec![f32::MIN; (image.width() * image.height()) as usize]; // 注意一定初始化为最小值
-
-    // let input = BufReader::new(File::open("obj/african_head/african_head.obj").unwrap());
-    let input = BufReader::new(File::open("obj/diablo3/diablo3_pose.obj").unwrap());
-    let model = obj::load_obj::<obj::TexturedVertex, _, u32>(input).unwrap();
-
-    let model_view = lookat(eye, center, up);
-    let model_view_light = lookat(light_dir, center, up);
-
-    #[rustfmt::skip]
-    let _projection = glm::mat4(
-        1., 0., 0., 0.,
-        0., 1., 0., 0.,
-        0., 0., 1., -1./ glm::distance(eye, center),
-        0., 0., 0., 1.);
-
-    let projection = glm::Mat4::one();
-
-    let view_port = viewport(
+    let model = Model::load(&args.model)?;
+    if let Some(path) = &args.bake_ao {
+        let meshes: Vec<_> = model.batches.iter().map(|b| &b.mesh).collect();
+        let ao = AoBake::default().bake(&meshes, width, height)?;
+        ao_bake::save(&ao, path)?;
+        return Ok(());
+    }
+    // 材质里没有贴图时才加载命令行指定的贴图
+    let fallback = |path: &str, needs: fn(&DrawBatch) -> bool| -> Result<Option<Texture>> {
+        if model.batches.iter().any(needs) {
+            Ok(Some(load_texture(path)?))
+        } else {
+            Ok(None)
+        }
+    };
+
+    let mut renderer = Renderer::new(width, height);
+    renderer.set_rasterizer_state(RasterizerState {
+        cull_mode: args.cull,
+        ..Default::default()
+    });
+    renderer.set_samples(args.samples);
+    renderer.set_supersampling(args.ssaa, args.ssaa_filter)?;
+    renderer.set_view_port(viewport(
         width as i32 / 8,
         height as i32 / 8,
         width as i32 * 3 / 4,
         height as i32 * 3 / 4,
-    );
+    ));
 
-    let m = view_port * projection * model_view;
+    let model_view = lookat(args.eye, args.center, args.up);
+    let projection = renderer.projection();
 
-    let mut _shader = GouraudShader::new(
-        &model, &diffus, model_view, projection, view_port, light_dir,
-    );
-    let mut _shader = PhongShader::new(&model, &diffus, &diffus_nm, &diffus_spec, m, light_dir);
-    let mut shader = ShadowShader::new(&model, model_view_light, projection, view_port);
-    for i in 0..model.indices.len() / 3 {
-        let mut clip_coords: [glm::Vec4; 3] = [glm::Vec4::zero(); 3];
-        for j in 0..3 {
-            clip_coords[j] = shader.vertex(i, j);
+    match args.shader {
+        ShaderKind::Gouraud => {
+            let diffuse = fallback(&args.diffuse, DrawBatch::needs_diffuse)?;
+            for batch in &model.batches {
+                let shader = GouraudShader::new(
+                    &batch.mesh,
+                    batch.diffuse(diffuse.as_ref()),
+                    model_view,
+                    projection,
+                    &lights,
+                )?
+                .with_sampler(args.sampler);
+                renderer.draw_mesh_parallel(&batch.mesh, &shader)?;
+            }
+        }
+        ShaderKind::Phong => {
+            let diffuse = fallback(&args.diffuse, DrawBatch::needs_diffuse)?;
+            let normal = fallback(&args.normal, DrawBatch::needs_normal)?;
+            let specular = fallback(&args.specular, DrawBatch::needs_specular)?;
+            let occlusion_map = args.ao_map.as_ref().map(load_texture).transpose()?;
+
+            // 第一遍: 从光源渲染阴影贴图
+            let shadow_map = render_shadow_map(&model, &lights[0], width, height)?;
+
+            // 环境光遮蔽: 先只渲染深度，计算完再清空
+            let ambient_occlusion = if args.ssao {
+                for batch in &model.batches {
+                    renderer.draw_depth(&batch.mesh, model_view)?;
+                }
+                let world_to_screen = renderer.render_view_port() * projection * model_view;
+                let ao = Ssao::default().compute(renderer.depth_buffer(), None, world_to_screen)?;
+                renderer.clear();
+                Some(ao)
+            } else {
+                None
+            };
+
+            // 第二遍: 着色时查询阴影贴图
+            for batch in &model.batches {
+                let normal = batch
+                    .normal(normal.as_ref())
+                    .context("phong shader needs a normal map")?;
+                let mut shader = PhongShader::new(
+                    &batch.mesh,
+                    batch.diffuse(diffuse.as_ref()),
+                    normal,
+                    batch.specular(specular.as_ref()),
+                    glm::Mat4::one(),
+                    projection * model_view,
+                    args.eye,
+                    &lights,
+                )?
+                .with_sampler(args.sampler)
+                .with_shadow(&shadow_map);
+                if args.normal_space == NormalSpace::Tangent {
+                    shader = shader.with_tangents(&batch.tangents);
+                }
+                if let Some(ao) = &ambient_occlusion {
+                    shader = shader.with_ambient_occlusion(ao);
+                }
+                if let Some(occlusion_map) = &occlusion_map {
+                    shader = shader.with_occlusion_map(occlusion_map);
+                }
+                renderer.draw_mesh_parallel(&batch.mesh, &shader)?;
+            }
+        }
+        ShaderKind::Pbr => {
+            let diffuse = fallback(&args.diffuse, DrawBatch::needs_diffuse)?;
+            let normal = fallback(&args.normal, DrawBatch::needs_normal)?;
+            let load = |path: &Option<String>| path.as_ref().map(load_texture).transpose();
+            let metallic_roughness = load(&args.metallic_roughness)?;
+            let occlusion = load(&args.ao_map)?;
+            let emissive = load(&args.emissive)?;
+
+            let shadow_map = render_shadow_map(&model, &lights[0], width, height)?;
+            let view_projection = projection * model_view;
+            let fallback = PbrMaterial {
+                base_color: diffuse.as_ref(),
+                metallic_roughness: metallic_roughness.as_ref(),
+                metallic_factor: args.metallic,
+                roughness_factor: args.roughness,
+                normal: normal.as_ref(),
+                occlusion: occlusion.as_ref(),
+                emissive: emissive.as_ref(),
+                ..Default::default()
+            };
+            for batch in &model.batches {
+                let mut shader = PbrShader::new(
+                    &batch.mesh,
+                    PbrMaterial::for_batch(batch, fallback),
+                    glm::Mat4::one(),
+                    view_projection,
+                    args.eye,
+                    &lights,
+                )?
+                .with_sampler(args.sampler)
+                .with_shadow(&shadow_map);
+                if args.normal_space == NormalSpace::Tangent {
+                    shader = shader.with_tangents(&batch.tangents);
+                }
+                renderer.draw_mesh_parallel(&batch.mesh, &shader)?;
+            }
+        }
+        ShaderKind::Deferred => {
+            let diffuse = fallback(&args.diffuse, DrawBatch::needs_diffuse)?;
+            let normal = fallback(&args.normal, DrawBatch::needs_normal)?;
+            let specular = fallback(&args.specular, DrawBatch::needs_specular)?;
+
+            // 第一遍: 把表面属性写入G-buffer，尺寸和渲染分辨率相同
+            let (w, h) = (renderer.image().width(), renderer.image().height());
+            let mut gbuffer = GBuffer::new(w, h);
+            let view_projection = projection * model_view;
+            for (i, batch) in model.batches.iter().enumerate() {
+                let normal = batch
+                    .normal(normal.as_ref())
+                    .context("deferred shader needs a normal map")?;
+                let mut shader = GBufferShader::new(
+                    &batch.mesh,
+                    batch.diffuse(diffuse.as_ref()),
+                    normal,
+                    batch.specular(specular.as_ref()),
+                    glm::Mat4::one(),
+                    view_projection,
+                )?
+                .with_sampler(args.sampler)
+                .with_material_id(i as u32 + 1);
+                if args.normal_space == NormalSpace::Tangent {
+                    shader = shader.with_tangents(&batch.tangents);
+                }
+                renderer.draw_mesh_to(&batch.mesh, &shader, &mut gbuffer)?;
+            }
+
+            // 环境光遮蔽使用G-buffer里的法线
+            let ambient_occlusion = if args.ssao {
+                let world_to_screen = renderer.render_view_port() * view_projection;
+                let normals = Some(gbuffer.normal());
+                Some(Ssao::default().compute(renderer.depth_buffer(), normals, world_to_screen)?)
+            } else {
+                None
+            };
+
+            // 第二遍: 每个像素计算一次光照，阴影和phong着色器一样
+            let shadow_map = render_shadow_map(&model, &lights[0], width, height)?;
+            let mut lights = ShadowedLights::new(&lights);
+            lights.set_shadow(0, &shadow_map);
+            deferred::shade(
+                &gbuffer,
+                &lights,
+                args.eye,
+                ambient_occlusion.as_ref(),
+                renderer.image_mut(),
+            );
+        }
+        ShaderKind::Shadow => {
+            for batch in &model.batches {
+                let shader =
+                    ShadowShader::new(&batch.mesh, model_view, projection, renderer.view_port());
+                renderer.draw_mesh_parallel(&batch.mesh, &shader)?;
+            }
         }
-        triangle_with_shader(
-            clip_coords[0],
-            clip_coords[1],
-            clip_coords[2],
-            &mut shader,
-            &mut image,
-            &mut zbuffer,
-        );
     }
 
-    flip_vertical_in_place(&mut image);
-    image.save("a.png").unwrap();
-    flip_vertical_in_place(&mut zbuffer);
-    zbuffer.save("b.png").unwrap();
+    renderer.save_image(&args.output)?;
+    renderer.save_depth(&args.depth_output)?;
+    Ok(())
+}
+
+// 从--light方向渲染模型的阴影贴图，尺寸和投影与主渲染相同
+// 光源方向上正交投影，刚好包住整个模型，光源和up平行时也能得到正确的视图
+fn render_shadow_map(model: &Model, light: &Light, width: u32, height: u32) -> Result<ShadowMap> {
+    let positions = model
+        .batches
+        .iter()
+        .flat_map(|batch| batch.mesh.vertices.iter())
+        .map(|v| *glm::Vec3::from_array(&v.position));
+    let (center, radius) = bounding_sphere(positions).unwrap_or((glm::vec3(0., 0., 0.), 1.));
+    let mut shadow_map = ShadowMap::for_light(
+        light,
+        center,
+        radius,
+        width,
+        height,
+        ShadowMap::DEFAULT_BIAS,
+    );
+    shadow_map.set_filter(ShadowFilter::Pcss {
+        kernel: PcfKernel::Poisson,
+        light_size: 8.,
+    });
+    for batch in &model.batches {
+        shadow_map.render(&batch.mesh)?;
+    }
+    Ok(shadow_map)
 }
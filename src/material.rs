@@ -0,0 +1,206 @@
+//! Wavefront MTL 材质
+
+use std::{
+    io::BufRead,
+    path::{Path, PathBuf},
+};
+
+use glm::Vec3;
+
+use crate::error::{RenderError, Result};
+
+/// MTL 中的一个材质，贴图路径已经相对 MTL 文件所在目录解析
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    pub name: String,
+    pub ambient: Vec3,                 // Ka
+    pub diffuse: Vec3,                 // Kd
+    pub specular: Vec3,                // Ks
+    pub shininess: f32,                // Ns 高光指数
+    pub dissolve: f32,                 // d 不透明度，1表示完全不透明
+    pub diffuse_map: Option<PathBuf>,  // map_Kd
+    pub normal_map: Option<PathBuf>,   // map_Bump / bump / norm
+    pub specular_map: Option<PathBuf>, // map_Ks
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            ambient: glm::vec3(0., 0., 0.),
+            diffuse: glm::vec3(1., 1., 1.),
+            specular: glm::vec3(0., 0., 0.),
+            shininess: 1.,
+            dissolve: 1.,
+            diffuse_map: None,
+            normal_map: None,
+            specular_map: None,
+        }
+    }
+}
+
+// 语句解析错误，行号和文件名由parse_mtl补上
+type StmtResult<T> = std::result::Result<T, String>;
+
+fn parse_f32(stmt: &str, arg: &str) -> StmtResult<f32> {
+    arg.parse()
+        .map_err(|_| format!("invalid number `{}` in `{}`", arg, stmt))
+}
+
+fn parse_color(stmt: &str, args: &[&str]) -> StmtResult<Vec3> {
+    match args {
+        [r] => {
+            let r = parse_f32(stmt, r)?;
+            Ok(glm::vec3(r, r, r))
+        }
+        [r, g, b] => Ok(glm::vec3(
+            parse_f32(stmt, r)?,
+            parse_f32(stmt, g)?,
+            parse_f32(stmt, b)?,
+        )),
+        _ => Err(format!("`{}` expects 1 or 3 values", stmt)),
+    }
+}
+
+// 贴图语句可能带 -bm 1.0 之类的选项，文件名是最后一个参数
+fn parse_map(stmt: &str, args: &[&str], base_dir: &Path) -> StmtResult<PathBuf> {
+    match args.last() {
+        Some(file) => Ok(base_dir.join(file)),
+        None => Err(format!("`{}` expects a file name", stmt)),
+    }
+}
+
+/// 解析 MTL 文件，path 是 MTL 文件的路径，用于解析贴图的相对路径和报错
+///
+/// 不认识的语句会被忽略
+pub fn parse_mtl<R: BufRead>(input: R, path: &Path) -> Result<Vec<Material>> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let error = |line: usize, message: String| RenderError::Mtl {
+        path: path.to_path_buf(),
+        line,
+        message,
+    };
+    let mut materials: Vec<Material> = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| RenderError::io(path, e))?;
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let stmt = match tokens.next() {
+            Some(stmt) => stmt,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+        if stmt == "newmtl" {
+            materials.push(Material {
+                name: args.join(" "),
+                ..Default::default()
+            });
+            continue;
+        }
+        let mat = match materials.last_mut() {
+            Some(mat) => mat,
+            None => return Err(error(i + 1, format!("`{}` before any `newmtl`", stmt))),
+        };
+        let parsed: StmtResult<()> = (|| {
+            match stmt {
+                "Ka" => mat.ambient = parse_color(stmt, &args)?,
+                "Kd" => mat.diffuse = parse_color(stmt, &args)?,
+                "Ks" => mat.specular = parse_color(stmt, &args)?,
+                "Ns" => mat.shininess = parse_f32(stmt, args.first().unwrap_or(&""))?,
+                "d" => mat.dissolve = parse_f32(stmt, args.first().unwrap_or(&""))?,
+                "Tr" => mat.dissolve = 1. - parse_f32(stmt, args.first().unwrap_or(&""))?,
+                "map_Kd" => mat.diffuse_map = Some(parse_map(stmt, &args, base_dir)?),
+                "map_Ks" => mat.specular_map = Some(parse_map(stmt, &args, base_dir)?),
+                "map_Bump" | "map_bump" | "bump" | "norm" => {
+                    mat.normal_map = Some(parse_map(stmt, &args, base_dir)?)
+                }
+                _ => {}
+            }
+            Ok(())
+        })();
+        parsed.map_err(|message| error(i + 1, message))?;
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Vec<Material>> {
+        parse_mtl(text.as_bytes(), Path::new("models/test.mtl"))
+    }
+
+    #[test]
+    fn parses_all_keys() {
+        let materials = parse(
+            "# 注释\n\
+             newmtl skin\n\
+             Ka 0.1 0.2 0.3\n\
+             Kd 0.5\n\
+             Ks 0.4 0.5 0.6 # 行尾注释\n\
+             Ns 32\n\
+             d 0.75\n\
+             map_Kd skin_diffuse.tga\n\
+             map_Ks -bm 1.0 skin_spec.tga\n\
+             map_Bump skin_nm.tga\n\
+             illum 2\n\
+             newmtl glass\n\
+             Tr 0.25\n",
+        )
+        .unwrap();
+        assert_eq!(materials.len(), 2);
+        let skin = &materials[0];
+        assert_eq!(skin.name, "skin");
+        assert_eq!(skin.ambient, glm::vec3(0.1, 0.2, 0.3));
+        assert_eq!(skin.diffuse, glm::vec3(0.5, 0.5, 0.5));
+        assert_eq!(skin.specular, glm::vec3(0.4, 0.5, 0.6));
+        assert_eq!(skin.shininess, 32.);
+        assert_eq!(skin.dissolve, 0.75);
+        let dir = Path::new("models");
+        assert_eq!(skin.diffuse_map, Some(dir.join("skin_diffuse.tga")));
+        assert_eq!(skin.specular_map, Some(dir.join("skin_spec.tga")));
+        assert_eq!(skin.normal_map, Some(dir.join("skin_nm.tga")));
+        let glass = &materials[1];
+        assert_eq!(glass.dissolve, 0.75);
+        assert_eq!(glass.diffuse_map, None);
+    }
+
+    #[test]
+    fn normal_map_aliases() {
+        for stmt in ["map_Bump", "map_bump", "bump", "norm"] {
+            let materials = parse(&format!("newmtl m\n{} nm.tga\n", stmt)).unwrap();
+            assert_eq!(
+                materials[0].normal_map,
+                Some(Path::new("models").join("nm.tga")),
+                "{}",
+                stmt
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_keys_and_comments_are_ignored() {
+        let materials = parse("\n# newmtl hidden\nnewmtl m\nKe 1 1 1\nNi 1.5\n").unwrap();
+        assert_eq!(materials.len(), 1);
+        assert_eq!(
+            materials[0],
+            Material {
+                name: "m".into(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reports_line_of_bad_statement() {
+        match parse("newmtl m\nKd 1 x 1\n") {
+            Err(RenderError::Mtl { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse("Kd 1 1 1\n") {
+            Err(RenderError::Mtl { line, .. }) => assert_eq!(line, 1),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
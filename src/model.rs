@@ -0,0 +1,540 @@
+//! 模型加载，按材质拆分成多个绘制批次
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use image::{imageops::flip_vertical_in_place, ImageBuffer, Rgba};
+use obj::{
+    raw::{object::Polygon, RawObj},
+    FromRawVertex, TexturedVertex,
+};
+
+use crate::{
+    any_perpendicular,
+    error::{RenderError, Result},
+    material::{parse_mtl, Material},
+};
+
+pub use crate::draw::texture::Texture;
+
+/// 加载贴图并生成mipmap，tga图像原点在左下角，加载后上下翻转
+pub fn load_texture<P: AsRef<Path>>(path: P) -> Result<Texture> {
+    let path = path.as_ref();
+    let mut texture = image::open(path)
+        .map_err(|e| RenderError::texture(path, e))?
+        .to_rgba8();
+    flip_vertical_in_place(&mut texture);
+    Ok(Texture::new(texture))
+}
+
+/// 检查索引缓冲是完整的三角形，并且没有越界的索引
+pub fn check_mesh(mesh: &obj::Obj<TexturedVertex, u32>) -> Result<()> {
+    if mesh.indices.len() % 3 != 0 {
+        return Err(RenderError::IncompleteTriangle(mesh.indices.len()));
+    }
+    let vertices = mesh.vertices.len();
+    match mesh.indices.iter().find(|&&i| i as usize >= vertices) {
+        Some(&index) => Err(RenderError::IndexOutOfRange {
+            index: index as usize,
+            vertices,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// 按MikkTSpace的约定为每个顶点生成切线，网格需要先通过check_mesh
+///
+/// xyz是和顶点法线正交的单位向量，指向u增大的方向，w是副切线的方向(±1)，
+/// 副切线为 w * cross(normal, tangent)。每个面的切线按顶点处的夹角加权累加
+pub fn generate_tangents(mesh: &obj::Obj<TexturedVertex, u32>) -> Vec<glm::Vec4> {
+    let zero = glm::vec3(0., 0., 0.);
+    let normalize = |v: glm::Vec3| {
+        let len = glm::length(v);
+        if len > f32::EPSILON {
+            v / len
+        } else {
+            zero
+        }
+    };
+    let vertex = |i: u32| &mesh.vertices[i as usize];
+    let mut tangents = vec![zero; mesh.vertices.len()];
+    let mut bitangents = vec![zero; mesh.vertices.len()];
+    for face in mesh.indices.chunks_exact(3) {
+        let p = [0, 1, 2].map(|k| {
+            let p = vertex(face[k]).position;
+            glm::vec3(p[0], p[1], p[2])
+        });
+        let uv = [0, 1, 2].map(|k| {
+            let t = vertex(face[k]).texture;
+            glm::vec2(t[0], t[1])
+        });
+        let (e1, e2) = (p[1] - p[0], p[2] - p[0]);
+        let (d1, d2) = (uv[1] - uv[0], uv[2] - uv[0]);
+        let det = d1.x * d2.y - d2.x * d1.y;
+        // uv退化的面没有确定的切线
+        if det.abs() <= f32::EPSILON {
+            continue;
+        }
+        let t = normalize((e1 * d2.y - e2 * d1.y) / det);
+        let b = normalize((e2 * d1.x - e1 * d2.x) / det);
+        for k in 0..3 {
+            let a = normalize(p[(k + 1) % 3] - p[k]);
+            let c = normalize(p[(k + 2) % 3] - p[k]);
+            let angle = glm::dot(a, c).clamp(-1., 1.).acos();
+            let i = face[k] as usize;
+            tangents[i] = tangents[i] + t * angle;
+            bitangents[i] = bitangents[i] + b * angle;
+        }
+    }
+    mesh.vertices
+        .iter()
+        .zip(tangents.iter().zip(&bitangents))
+        .map(|(v, (&t, &b))| {
+            let n = normalize(glm::vec3(v.normal[0], v.normal[1], v.normal[2]));
+            // Gram-Schmidt正交化，得不到切线时任取一个和法线垂直的方向
+            let mut tangent = normalize(t - n * glm::dot(n, t));
+            if tangent == zero {
+                tangent = any_perpendicular(n);
+            }
+            let w = if glm::dot(glm::cross(n, tangent), b) < 0. {
+                -1.
+            } else {
+                1.
+            };
+            tangent.extend(w)
+        })
+        .collect()
+}
+
+// 用颜色生成1x1的贴图，材质没有贴图时使用
+fn solid_texture(color: glm::Vec3) -> Texture {
+    let c = |v: f32| (v.clamp(0., 1.) * 255.) as u8;
+    Texture::new(ImageBuffer::from_pixel(
+        1,
+        1,
+        Rgba([c(color.x), c(color.y), c(color.z), 255]),
+    ))
+}
+
+/// 使用同一个材质的一组面
+pub struct DrawBatch {
+    pub material: Material,
+    pub mesh: obj::Obj<TexturedVertex, u32>,
+    pub diffuse_map: Option<Arc<Texture>>,
+    pub normal_map: Option<Arc<Texture>>,
+    pub specular_map: Option<Arc<Texture>>,
+    /// 每个顶点的切线，见generate_tangents
+    pub tangents: Vec<glm::Vec4>,
+    diffuse_color: Texture,  // Kd
+    specular_color: Texture, // Ns，PhongShader从红色通道读取高光指数
+    from_mtl: bool,          // 材质是否来自MTL文件
+}
+
+impl DrawBatch {
+    fn new(material: Material, mesh: obj::Obj<TexturedVertex, u32>, from_mtl: bool) -> Self {
+        let shininess = material.shininess.clamp(0., 255.) / 255.;
+        Self {
+            diffuse_color: solid_texture(material.diffuse),
+            specular_color: solid_texture(glm::vec3(shininess, shininess, shininess)),
+            material,
+            tangents: generate_tangents(&mesh),
+            mesh,
+            diffuse_map: None,
+            normal_map: None,
+            specular_map: None,
+            from_mtl,
+        }
+    }
+
+    /// 是否需要外部提供漫反射贴图
+    pub fn needs_diffuse(&self) -> bool {
+        self.diffuse_map.is_none() && !self.from_mtl
+    }
+
+    /// 是否需要外部提供法线贴图
+    pub fn needs_normal(&self) -> bool {
+        self.normal_map.is_none()
+    }
+
+    /// 是否需要外部提供高光贴图
+    pub fn needs_specular(&self) -> bool {
+        self.specular_map.is_none() && !self.from_mtl
+    }
+
+    /// 漫反射贴图，优先使用材质的map_Kd，其次是材质的Kd颜色
+    ///
+    /// 没有MTL材质时使用fallback
+    pub fn diffuse<'a>(&'a self, fallback: Option<&'a Texture>) -> &'a Texture {
+        match self.diffuse_map.as_deref() {
+            Some(map) => map,
+            None if self.from_mtl => &self.diffuse_color,
+            None => fallback.unwrap_or(&self.diffuse_color),
+        }
+    }
+
+    /// 法线贴图，优先使用材质的map_Bump
+    pub fn normal<'a>(&'a self, fallback: Option<&'a Texture>) -> Option<&'a Texture> {
+        self.normal_map.as_deref().or(fallback)
+    }
+
+    /// 高光贴图，优先使用材质的map_Ks，其次是材质的Ns
+    ///
+    /// 没有MTL材质时使用fallback
+    pub fn specular<'a>(&'a self, fallback: Option<&'a Texture>) -> &'a Texture {
+        match self.specular_map.as_deref() {
+            Some(map) => map,
+            None if self.from_mtl => &self.specular_color,
+            None => fallback.unwrap_or(&self.specular_color),
+        }
+    }
+}
+
+/// 从 OBJ 加载的模型，包含 mtllib 引用的材质
+pub struct Model {
+    pub batches: Vec<DrawBatch>,
+}
+
+impl Model {
+    /// 加载 OBJ 以及它引用的 MTL 和贴图，按 usemtl 拆分成绘制批次
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Model> {
+        let path = path.as_ref();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let obj_error = |source| RenderError::Obj {
+            path: path.to_path_buf(),
+            source,
+        };
+        let input = BufReader::new(File::open(path).map_err(|e| RenderError::io(path, e))?);
+        let raw = obj::raw::parse_obj(input).map_err(obj_error)?;
+
+        let mut materials = HashMap::new();
+        for lib in &raw.material_libraries {
+            let lib_path = base_dir.join(lib);
+            let input =
+                BufReader::new(File::open(&lib_path).map_err(|e| RenderError::io(&lib_path, e))?);
+            for material in parse_mtl(input, &lib_path)? {
+                materials.insert(material.name.clone(), material);
+            }
+        }
+
+        // 按第一个面出现的顺序排列，保证绘制顺序和文件一致
+        let mut groups: Vec<_> = raw
+            .meshes
+            .iter()
+            .filter_map(|(name, group)| {
+                let first = group.polygons.iter().find(|r| r.end > r.start)?;
+                Some((first.start, name, group))
+            })
+            .collect();
+        groups.sort_by_key(|(first, _, _)| *first);
+
+        let mut textures: HashMap<PathBuf, Arc<Texture>> = HashMap::new();
+        let mut load = |path: &Option<PathBuf>| -> Result<Option<Arc<Texture>>> {
+            let path = match path {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+            if !textures.contains_key(path) {
+                textures.insert(path.clone(), Arc::new(load_texture(path)?));
+            }
+            Ok(textures.get(path).cloned())
+        };
+
+        let mut batches = Vec::with_capacity(groups.len());
+        for (_, name, group) in groups {
+            let polygons = group
+                .polygons
+                .iter()
+                .flat_map(|r| raw.polygons[r.start..r.end].iter());
+            let batch = BatchData::collect(&raw, polygons)?;
+            let (vertices, indices) = TexturedVertex::process(
+                batch.positions.data,
+                batch.normals.data,
+                batch.tex_coords.data,
+                batch.polygons,
+            )
+            .map_err(obj_error)?;
+            let mesh = obj::Obj {
+                name: raw.name.clone(),
+                vertices,
+                indices,
+            };
+            check_mesh(&mesh)?;
+            // 没有usemtl或者MTL里找不到的材质使用默认材质
+            let mut batch = match materials.get(name) {
+                Some(material) => DrawBatch::new(material.clone(), mesh, true),
+                None => {
+                    let material = Material {
+                        name: name.clone(),
+                        ..Default::default()
+                    };
+                    DrawBatch::new(material, mesh, false)
+                }
+            };
+            batch.diffuse_map = load(&batch.material.diffuse_map)?;
+            batch.normal_map = load(&batch.material.normal_map)?;
+            batch.specular_map = load(&batch.material.specular_map)?;
+            batches.push(batch);
+        }
+        Ok(Model { batches })
+    }
+}
+
+// 一种顶点属性中被用到的部分，按第一次用到的顺序重新编号
+struct Remap<T> {
+    data: Vec<T>,
+    index: HashMap<usize, usize>,
+}
+
+impl<T: Copy> Remap<T> {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    // 原数组中第i个元素的新编号
+    fn get(&mut self, src: &[T], i: usize) -> Result<usize> {
+        if let Some(&j) = self.index.get(&i) {
+            return Ok(j);
+        }
+        let value = *src.get(i).ok_or(RenderError::IndexOutOfRange {
+            index: i,
+            vertices: src.len(),
+        })?;
+        self.data.push(value);
+        self.index.insert(i, self.data.len() - 1);
+        Ok(self.data.len() - 1)
+    }
+}
+
+// 一个绘制批次的面和它们用到的顶点属性，不用复制整个模型的顶点数组
+struct BatchData {
+    positions: Remap<(f32, f32, f32, f32)>,
+    normals: Remap<(f32, f32, f32)>,
+    tex_coords: Remap<(f32, f32, f32)>,
+    polygons: Vec<Polygon>,
+}
+
+impl BatchData {
+    fn collect<'a>(raw: &RawObj, polygons: impl Iterator<Item = &'a Polygon>) -> Result<Self> {
+        let mut batch = BatchData {
+            positions: Remap::new(),
+            normals: Remap::new(),
+            tex_coords: Remap::new(),
+            polygons: Vec::new(),
+        };
+        for polygon in polygons {
+            let polygon = batch.remap(raw, polygon)?;
+            batch.polygons.push(polygon);
+        }
+        Ok(batch)
+    }
+
+    fn remap(&mut self, raw: &RawObj, polygon: &Polygon) -> Result<Polygon> {
+        let (p, t, n) = (&raw.positions, &raw.tex_coords, &raw.normals);
+        Ok(match polygon {
+            Polygon::P(v) => Polygon::P(
+                v.iter()
+                    .map(|&pi| self.positions.get(p, pi))
+                    .collect::<Result<_>>()?,
+            ),
+            Polygon::PT(v) => Polygon::PT(
+                v.iter()
+                    .map(|&(pi, ti)| Ok((self.positions.get(p, pi)?, self.tex_coords.get(t, ti)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            Polygon::PN(v) => Polygon::PN(
+                v.iter()
+                    .map(|&(pi, ni)| Ok((self.positions.get(p, pi)?, self.normals.get(n, ni)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            Polygon::PTN(v) => Polygon::PTN(
+                v.iter()
+                    .map(|&(pi, ti, ni)| {
+                        Ok((
+                            self.positions.get(p, pi)?,
+                            self.tex_coords.get(t, ti)?,
+                            self.normals.get(n, ni)?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // 在临时目录写入测试文件，返回目录
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("tinyrenderer-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn load_splits_batches_by_material() {
+        let dir = write_files(
+            "split",
+            &[
+                (
+                    "quad.mtl",
+                    "newmtl red\nKd 1 0 0\nNs 10\nnewmtl blue\nKd 0 0 1\n",
+                ),
+                (
+                    "quad.obj",
+                    "mtllib quad.mtl\n\
+                     v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 2 0 0\n\
+                     vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+                     vn 0 0 1\n\
+                     usemtl blue\n\
+                     f 1/1/1 2/2/1 3/3/1\n\
+                     f 1/1/1 3/3/1 4/4/1\n\
+                     usemtl red\n\
+                     f 2/1/1 5/2/1 3/3/1\n\
+                     usemtl missing\n\
+                     f 1/1/1 5/2/1 4/4/1\n",
+                ),
+            ],
+        );
+        let model = Model::load(dir.join("quad.obj")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = model
+            .batches
+            .iter()
+            .map(|b| b.material.name.as_str())
+            .collect();
+        assert_eq!(names, ["blue", "red", "missing"]);
+        let blue = &model.batches[0];
+        assert_eq!(blue.material.diffuse, glm::vec3(0., 0., 1.));
+        assert_eq!(blue.mesh.indices.len(), 6);
+        // 每个批次只包含它用到的顶点
+        assert_eq!(blue.mesh.vertices.len(), 4);
+        let red = &model.batches[1];
+        assert_eq!(red.material.shininess, 10.);
+        assert_eq!(red.mesh.indices.len(), 3);
+        assert_eq!(red.mesh.vertices.len(), 3);
+        let positions: Vec<_> = red
+            .mesh
+            .indices
+            .iter()
+            .map(|&i| red.mesh.vertices[i as usize].position)
+            .collect();
+        assert_eq!(positions, [[1., 0., 0.], [2., 0., 0.], [1., 1., 0.]]);
+        assert!(model.batches[2].needs_diffuse());
+        assert!(!blue.needs_diffuse());
+    }
+
+    #[test]
+    fn load_rejects_out_of_range_index() {
+        let dir = write_files(
+            "range",
+            &[(
+                "bad.obj",
+                "v 0 0 0\nv 1 0 0\nv 1 1 0\nvt 0 0\nvn 0 0 1\nf 1/1/1 2/1/1 4/1/1\n",
+            )],
+        );
+        let result = Model::load(dir.join("bad.obj"));
+        fs::remove_dir_all(&dir).unwrap();
+        // 解析OBJ时就会发现越界的索引
+        assert!(matches!(result, Err(RenderError::Obj { .. })));
+    }
+
+    // xy平面上的单位正方形，两个三角形都是逆时针
+    fn quad(
+        uv: impl Fn(f32, f32) -> [f32; 2],
+        normals: [[f32; 3]; 4],
+    ) -> obj::Obj<TexturedVertex, u32> {
+        let corners = [(0., 0.), (1., 0.), (1., 1.), (0., 1.)];
+        obj::Obj {
+            name: None,
+            vertices: corners
+                .iter()
+                .zip(normals)
+                .map(|(&(x, y), normal)| {
+                    let [u, v] = uv(x, y);
+                    TexturedVertex {
+                        position: [x, y, 0.],
+                        normal,
+                        texture: [u, v, 0.],
+                    }
+                })
+                .collect(),
+            indices: vec![0, 1, 2, 0, 2, 3],
+        }
+    }
+
+    fn assert_near(actual: glm::Vec4, expected: glm::Vec4) {
+        let d = actual - expected;
+        assert!(
+            d.x.abs() < 1e-5 && d.y.abs() < 1e-5 && d.z.abs() < 1e-5 && d.w == 0.,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn tangents_are_orthonormal_to_normals() {
+        // 顶点法线各自倾斜，切线需要正交化到每个顶点的法线上
+        let normals = [
+            [-0.3, -0.2, 1.],
+            [0.4, 0., 1.],
+            [0.2, 0.5, 0.8],
+            [0., 0.3, 1.],
+        ];
+        let mesh = quad(|x, y| [x, y], normals);
+        let tangents = generate_tangents(&mesh);
+        assert_eq!(tangents.len(), 4);
+        for (t, n) in tangents.iter().zip(normals) {
+            let n = glm::normalize(*glm::Vec3::from_array(&n));
+            let xyz = glm::vec3(t.x, t.y, t.z);
+            assert!((glm::length(xyz) - 1.).abs() < 1e-5, "{:?}", t);
+            assert!(glm::dot(xyz, n).abs() < 1e-5, "{:?}", t);
+            assert!(t.x > 0.9, "{:?}", t);
+            assert_eq!(t.w, 1.);
+        }
+    }
+
+    #[test]
+    fn mirrored_uv_flips_handedness() {
+        let normals = [[0., 0., 1.]; 4];
+        let tangents = generate_tangents(&quad(|x, y| [x, y], normals));
+        for &t in &tangents {
+            assert_near(t, glm::vec4(1., 0., 0., 1.));
+        }
+        // u沿-x增大，v不变: 切线反向，cross(n, t)指向-y而副切线仍指向+y
+        let tangents = generate_tangents(&quad(|x, y| [1. - x, y], normals));
+        for &t in &tangents {
+            assert_near(t, glm::vec4(-1., 0., 0., -1.));
+        }
+    }
+
+    #[test]
+    fn degenerate_uv_still_gets_a_perpendicular_tangent() {
+        let tangents = generate_tangents(&quad(|_, _| [0.5, 0.5], [[0., 0., 1.]; 4]));
+        for t in tangents {
+            assert!(
+                t.z.abs() < 1e-5 && (t.x * t.x + t.y * t.y - 1.).abs() < 1e-5,
+                "{:?}",
+                t
+            );
+            assert!(t.w.abs() == 1., "{:?}", t);
+        }
+    }
+}
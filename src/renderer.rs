@@ -0,0 +1,525 @@
+use std::{path::Path, thread};
+
+use glm::Mat4;
+use image::{imageops::flip_vertical_in_place, ImageBuffer, Rgba};
+use num::One;
+use obj::TexturedVertex;
+
+use crate::{
+    draw::{
+        depth::{DepthBuffer, DepthFunc},
+        msaa::{MsaaBuffer, SampleCount},
+        ssaa::{self, downsample_color, downsample_depth, DownsampleFilter},
+        target::RenderTarget,
+        tile::draw_tiled,
+        triangle_with_shader, viewport, Interpolation, RasterizerState,
+    },
+    error::{RenderError, Result},
+    model::check_mesh,
+    our_gl::{shader_impl_shadow_shader::ShadowShader, IShader},
+    BLACK,
+};
+
+type Image = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+/// 渲染上下文，持有帧缓冲、深度缓冲、视口和投影矩阵
+pub struct Renderer {
+    width: u32,
+    height: u32,
+    image: Image, // 渲染分辨率，开启超采样时是输出尺寸的ssaa倍
+    zbuffer: DepthBuffer<f32>,
+    msaa: Option<MsaaBuffer<f32>>, // 开启多重采样时绘制到这里，resolve后写入image和zbuffer
+    view_port: Mat4,
+    projection: Mat4,
+    rasterizer: RasterizerState,
+    clear_color: Rgba<u8>,
+    threads: usize,
+    ssaa: u32,
+    ssaa_filter: DownsampleFilter,
+}
+
+impl Renderer {
+    /// 默认视口覆盖整张图，投影为单位矩阵，深度越大离摄像机越近
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            image: ImageBuffer::from_pixel(width, height, BLACK),
+            zbuffer: DepthBuffer::new(width, height, DepthFunc::GreaterEqual, 0.),
+            msaa: None,
+            view_port: viewport(0, 0, width as i32, height as i32),
+            projection: Mat4::one(),
+            rasterizer: RasterizerState::default(),
+            clear_color: BLACK,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            ssaa: 1,
+            ssaa_filter: DownsampleFilter::Box,
+        }
+    }
+
+    /// 输出图像的宽度
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn view_port(&self) -> Mat4 {
+        self.view_port
+    }
+
+    pub fn set_view_port(&mut self, view_port: Mat4) {
+        self.view_port = view_port;
+    }
+
+    /// 渲染分辨率的视口，开启超采样时是放大后的view_port
+    pub fn render_view_port(&self) -> Mat4 {
+        let f = self.ssaa as f32;
+        glm::ext::scale(&Mat4::one(), glm::vec3(f, f, 1.)) * self.view_port
+    }
+
+    /// 着色器构造时需要用同一个投影矩阵
+    pub fn projection(&self) -> Mat4 {
+        self.projection
+    }
+
+    /// 默认的深度测试是近处深度大，适合单位矩阵和 draw::reversed_z 的投影
+    ///
+    /// 直接使用 draw::perspective 等投影时，深度测试要改成 DepthFunc::Less 并清空为1
+    pub fn set_projection(&mut self, projection: Mat4) {
+        self.projection = projection;
+    }
+
+    /// 剔除、环绕方向和插值方式，默认不剔除
+    pub fn rasterizer_state(&self) -> RasterizerState {
+        self.rasterizer
+    }
+
+    pub fn set_rasterizer_state(&mut self, state: RasterizerState) {
+        self.rasterizer = state;
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.rasterizer.interpolation
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.rasterizer.interpolation = interpolation;
+    }
+
+    /// draw_mesh_parallel 使用的线程数，默认为CPU核数
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    pub fn set_clear_color(&mut self, color: Rgba<u8>) {
+        self.clear_color = color;
+    }
+
+    /// 超采样倍数和缩小时使用的滤波器，默认1倍不开启超采样
+    pub fn supersampling(&self) -> (u32, DownsampleFilter) {
+        (self.ssaa, self.ssaa_filter)
+    }
+
+    /// 按factor倍的分辨率渲染，输出时用filter缩小，factor为1时关闭
+    ///
+    /// 颜色和深度缓冲会用清除值重新创建，factor超过 ssaa::MAX_FACTOR
+    /// 或放大后的尺寸溢出时返回错误，设置保持不变
+    pub fn set_supersampling(&mut self, factor: u32, filter: DownsampleFilter) -> Result<()> {
+        let factor = factor.max(1);
+        let size = (
+            self.width.checked_mul(factor),
+            self.height.checked_mul(factor),
+        );
+        let (w, h) = match size {
+            (Some(w), Some(h)) if factor <= ssaa::MAX_FACTOR && w.checked_mul(h).is_some() => {
+                (w, h)
+            }
+            _ => return Err(RenderError::SupersamplingFactor(factor)),
+        };
+        self.ssaa = factor;
+        self.ssaa_filter = filter;
+        self.image = ImageBuffer::from_pixel(w, h, self.clear_color);
+        let mut zbuffer = DepthBuffer::new(w, h, self.zbuffer.func(), self.zbuffer.clear_value());
+        zbuffer.set_write_enabled(self.zbuffer.write_enabled());
+        self.zbuffer = zbuffer;
+        self.set_samples(self.samples());
+        Ok(())
+    }
+
+    /// 每个像素的采样点数，默认X1不开启多重采样
+    pub fn samples(&self) -> SampleCount {
+        self.msaa
+            .as_ref()
+            .map_or(SampleCount::X1, MsaaBuffer::samples)
+    }
+
+    /// 开启或关闭多重采样，多重采样缓冲会用清除值重新创建
+    ///
+    /// 深度测试设置和depth_buffer保持一致
+    pub fn set_samples(&mut self, samples: SampleCount) {
+        self.msaa = match samples {
+            SampleCount::X1 => None,
+            _ => Some(MsaaBuffer::new(
+                self.image.width(),
+                self.image.height(),
+                samples,
+                self.clear_color,
+                self.zbuffer.func(),
+                self.zbuffer.clear_value(),
+            )),
+        };
+    }
+
+    pub fn msaa_buffer(&self) -> Option<&MsaaBuffer<f32>> {
+        self.msaa.as_ref()
+    }
+
+    /// 清空颜色和深度
+    pub fn clear(&mut self) {
+        let color = self.clear_color;
+        self.image.pixels_mut().for_each(|p| *p = color);
+        self.zbuffer.clear();
+        if let Some(msaa) = &mut self.msaa {
+            msaa.depth_mut().set_clear_value(self.zbuffer.clear_value());
+            msaa.clear(color);
+        }
+    }
+
+    /// 把多重采样缓冲的颜色平均、深度取最近的采样点，写入image和depth_buffer，没有开启多重采样时什么都不做
+    pub fn resolve(&mut self) {
+        if let Some(msaa) = &self.msaa {
+            self.image = msaa.resolve_color();
+            self.zbuffer = msaa.resolve_depth();
+        }
+    }
+
+    /// 渲染分辨率的图像，开启多重采样时需要先调用resolve
+    ///
+    /// 开启超采样时是放大后的图像，缩小后的结果见output_image
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// 开启多重采样时output_image从多重采样缓冲resolve，这里的修改不会出现在输出中
+    pub fn image_mut(&mut self) -> &mut Image {
+        &mut self.image
+    }
+
+    pub fn depth_buffer(&self) -> &DepthBuffer<f32> {
+        &self.zbuffer
+    }
+
+    pub fn depth_buffer_mut(&mut self) -> &mut DepthBuffer<f32> {
+        &mut self.zbuffer
+    }
+
+    // 绘制的目标，开启多重采样时是按采样点存储的缓冲
+    fn targets(&mut self) -> (SampleCount, &mut Image, &mut DepthBuffer<f32>) {
+        match &mut self.msaa {
+            Some(msaa) => {
+                let depth = msaa.depth_mut();
+                depth.set_func(self.zbuffer.func());
+                depth.set_write_enabled(self.zbuffer.write_enabled());
+                let samples = msaa.samples();
+                let (image, depth) = msaa.buffers_mut();
+                (samples, image, depth)
+            }
+            None => (SampleCount::X1, &mut self.image, &mut self.zbuffer),
+        }
+    }
+
+    /// 用着色器绘制模型的所有面，索引越界时不绘制任何面
+    ///
+    /// 每个顶点只运行一次顶点着色器，共享顶点的面使用同一个结果
+    pub fn draw_mesh<S: IShader<Output = Rgba<u8>>>(
+        &mut self,
+        mesh: &obj::Obj<TexturedVertex, u32>,
+        shader: &S,
+    ) -> Result<()> {
+        check_mesh(mesh)?;
+        let vertices = shade_vertices(mesh, shader);
+        let (view_port, state) = (self.render_view_port(), self.rasterizer);
+        let (samples, image, zbuffer) = self.targets();
+        for face in mesh.indices.chunks_exact(3) {
+            triangle_with_shader(
+                [0, 1, 2].map(|j| vertices[face[j] as usize]),
+                &view_port,
+                &state,
+                samples,
+                shader,
+                image,
+                zbuffer,
+            );
+        }
+        Ok(())
+    }
+
+    /// 同draw_mesh，按块分给多个线程绘制，结果和draw_mesh完全一致
+    pub fn draw_mesh_parallel<S>(
+        &mut self,
+        mesh: &obj::Obj<TexturedVertex, u32>,
+        shader: &S,
+    ) -> Result<()>
+    where
+        S: IShader<Output = Rgba<u8>> + Sync,
+        S::Varyings: Send + Sync,
+    {
+        check_mesh(mesh)?;
+        let vertices = shade_vertices(mesh, shader);
+        let (view_port, state, threads) = (self.render_view_port(), self.rasterizer, self.threads);
+        let (samples, image, zbuffer) = self.targets();
+        draw_tiled(
+            &vertices,
+            &mesh.indices,
+            &view_port,
+            &state,
+            samples,
+            threads,
+            shader,
+            image,
+            zbuffer,
+        );
+        Ok(())
+    }
+
+    /// 同draw_mesh_parallel，片段着色器的输出写入target，比如 deferred::GBuffer
+    ///
+    /// 使用depth_buffer做深度测试，target的尺寸需要和渲染分辨率相同
+    ///
+    /// 结果通常要再写回image_mut，而开启多重采样时输出来自多重采样缓冲，所以返回错误
+    pub fn draw_mesh_to<T, S>(
+        &mut self,
+        mesh: &obj::Obj<TexturedVertex, u32>,
+        shader: &S,
+        target: &mut T,
+    ) -> Result<()>
+    where
+        T: RenderTarget<Value = S::Output> + Send + Sync,
+        S: IShader + Sync,
+        S::Varyings: Send + Sync,
+    {
+        if self.msaa.is_some() {
+            return Err(RenderError::MultisampleUnsupported("draw_mesh_to"));
+        }
+        self.draw_single_sample(mesh, shader, target)
+    }
+
+    // 不使用多重采样绘制到target，深度测试使用depth_buffer
+    fn draw_single_sample<T, S>(
+        &mut self,
+        mesh: &obj::Obj<TexturedVertex, u32>,
+        shader: &S,
+        target: &mut T,
+    ) -> Result<()>
+    where
+        T: RenderTarget<Value = S::Output> + Send + Sync,
+        S: IShader + Sync,
+        S::Varyings: Send + Sync,
+    {
+        let expected = (self.zbuffer.width(), self.zbuffer.height());
+        let found = (target.width(), target.height());
+        if found != expected {
+            return Err(RenderError::TargetSize { expected, found });
+        }
+        check_mesh(mesh)?;
+        let vertices = shade_vertices(mesh, shader);
+        draw_tiled(
+            &vertices,
+            &mesh.indices,
+            &self.render_view_port(),
+            &self.rasterizer,
+            SampleCount::X1,
+            self.threads,
+            shader,
+            target,
+            &mut self.zbuffer,
+        );
+        Ok(())
+    }
+
+    /// 只写depth_buffer的预渲染，颜色不变，用于 ssao::Ssao 等需要先得到深度的效果
+    ///
+    /// model_view是模型到摄像机的变换，投影使用projection，不使用多重采样，开启多重采样时也可以调用
+    pub fn draw_depth(
+        &mut self,
+        mesh: &obj::Obj<TexturedVertex, u32>,
+        model_view: Mat4,
+    ) -> Result<()> {
+        let shader = ShadowShader::new(mesh, model_view, self.projection, self.render_view_port());
+        let mut image = ImageBuffer::new(self.zbuffer.width(), self.zbuffer.height());
+        self.draw_single_sample(mesh, &shader, &mut image)
+    }
+
+    /// 最终输出的颜色图像，先resolve多重采样，再把超采样缩小到输出尺寸
+    pub fn output_image(&self) -> Image {
+        let image = match &self.msaa {
+            Some(msaa) => msaa.resolve_color(),
+            None => self.image.clone(),
+        };
+        match self.ssaa {
+            1 => image,
+            factor => downsample_color(&image, factor, self.ssaa_filter),
+        }
+    }
+
+    /// 最终输出的深度，处理方式同output_image
+    pub fn output_depth(&self) -> DepthBuffer<f32> {
+        let depth = match &self.msaa {
+            Some(msaa) => msaa.resolve_depth(),
+            None => self.zbuffer.clone(),
+        };
+        match self.ssaa {
+            1 => depth,
+            factor => downsample_depth(&depth, factor),
+        }
+    }
+
+    /// 保存颜色图像，原点在左下角，保存前上下翻转
+    pub fn save_image<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut image = self.output_image();
+        flip_vertical_in_place(&mut image);
+        image.save(path).map_err(|source| RenderError::Save {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// 保存深度的可视化灰度图
+    pub fn save_depth<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut image = self.output_depth().to_luma8();
+        flip_vertical_in_place(&mut image);
+        image.save(path).map_err(|source| RenderError::Save {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+// 对每个顶点运行一次顶点着色器
+fn shade_vertices<S: IShader>(
+    mesh: &obj::Obj<TexturedVertex, u32>,
+    shader: &S,
+) -> Vec<(glm::Vec4, S::Varyings)> {
+    (0..mesh.vertices.len()).map(|i| shader.vertex(i)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use glm::{Vec2, Vec4};
+    use obj::Obj;
+
+    use super::*;
+    use crate::Fragment;
+
+    // 颜色取自uv，uv.x大于0.9的片段被丢弃
+    struct UvShader<'a> {
+        mesh: &'a Obj<TexturedVertex, u32>,
+    }
+
+    impl IShader for UvShader<'_> {
+        type Varyings = Vec2;
+        type Output = Rgba<u8>;
+
+        fn vertex(&self, i_vert: usize) -> (Vec4, Vec2) {
+            let v = self.mesh.vertices[i_vert];
+            let [x, y, z] = v.position;
+            (
+                glm::vec4(x, y, z, 1.),
+                glm::vec2(v.texture[0], v.texture[1]),
+            )
+        }
+
+        fn fragment(&self, frag: &Fragment<Vec2>) -> Option<Rgba<u8>> {
+            let uv = frag.varyings;
+            if uv.x > 0.9 {
+                return None;
+            }
+            Some(Rgba([(uv.x * 255.) as u8, (uv.y * 255.) as u8, 128, 255]))
+        }
+    }
+
+    // 几个互相穿插的大三角形，覆盖多个块，还有一个超出屏幕需要裁剪
+    fn mesh() -> Obj<TexturedVertex, u32> {
+        let vertex = |x: f32, y: f32, z: f32, u: f32, v: f32| TexturedVertex {
+            position: [x, y, z],
+            normal: [0., 0., 1.],
+            texture: [u, v, 0.],
+        };
+        Obj {
+            name: None,
+            vertices: vec![
+                vertex(-0.9, -0.8, -0.5, 0., 0.),
+                vertex(0.95, -0.6, 0.5, 1., 0.),
+                vertex(-0.2, 0.9, 0., 0.5, 1.),
+                vertex(-0.8, 0.7, 0.6, 0., 1.),
+                vertex(0.9, 0.8, -0.6, 1., 1.),
+                vertex(0.1, -0.95, 0., 0.3, 0.),
+                vertex(-1.5, 0., 0.2, 0., 0.5),
+                vertex(0.3, 0.01, 0.2, 0.8, 0.5),
+                vertex(-1.5, 0.3, 0.2, 0., 0.6),
+            ],
+            indices: vec![0, 1, 2, 3, 5, 4, 6, 7, 8],
+        }
+    }
+
+    #[test]
+    fn default_depth_is_reversed_z() {
+        // 深度越大越近，清空为0，开启多重采样时沿用同样的设置
+        let mut renderer = Renderer::new(4, 4);
+        let depth = renderer.depth_buffer();
+        assert_eq!(depth.func(), DepthFunc::GreaterEqual);
+        assert_eq!(depth.clear_value(), 0.);
+        assert!(depth.as_slice().iter().all(|&d| d == 0.));
+        renderer.set_samples(SampleCount::X4);
+        let msaa = renderer.msaa_buffer().unwrap().depth();
+        assert_eq!(
+            (msaa.func(), msaa.clear_value()),
+            (DepthFunc::GreaterEqual, 0.)
+        );
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let mesh = mesh();
+        let shader = UvShader { mesh: &mesh };
+        for samples in [SampleCount::X1, SampleCount::X4] {
+            let render = |parallel: bool| {
+                let mut renderer = Renderer::new(300, 200);
+                renderer.set_threads(4);
+                renderer.set_samples(samples);
+                if parallel {
+                    renderer.draw_mesh_parallel(&mesh, &shader).unwrap();
+                } else {
+                    renderer.draw_mesh(&mesh, &shader).unwrap();
+                }
+                renderer
+            };
+            let (serial, parallel) = (render(false), render(true));
+            match (serial.msaa_buffer(), parallel.msaa_buffer()) {
+                (Some(a), Some(b)) => {
+                    assert!(a.color() == b.color(), "{:?}", samples);
+                    assert!(
+                        a.depth().as_slice() == b.depth().as_slice(),
+                        "{:?}",
+                        samples
+                    );
+                }
+                _ => {
+                    assert!(serial.image() == parallel.image());
+                    assert!(serial.depth_buffer().as_slice() == parallel.depth_buffer().as_slice());
+                }
+            }
+            // 确认确实画到了图上
+            assert!(serial.output_image().pixels().any(|p| p.0[2] == 128));
+        }
+    }
+}
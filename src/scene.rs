@@ -0,0 +1,743 @@
+//! 场景描述文件(TOML/JSON)
+//!
+//! ```toml
+//! output_dir = "out"
+//!
+//! [settings]
+//! width = 800
+//! height = 800
+//! cull = "back"
+//! msaa = 4
+//! ssaa = 2
+//! ssaa_filter = "mitchell"
+//! texture_filter = "bilinear"
+//! texture_wrap = "repeat"
+//! mipmap = "linear"
+//! anisotropy = 4
+//! shadow_filter = "pcss"
+//! ssao = true
+//!
+//! [[lights]]
+//! type = "directional"
+//! direction = [1.0, 1.0, 0.0]
+//!
+//! [[lights]]
+//! type = "spot"
+//! position = [1.0, 2.0, 2.0]
+//! direction = [-0.5, -1.0, -1.0]
+//! color = [1.0, 0.8, 0.6]
+//! intensity = 2.0
+//! attenuation = [1.0, 0.0, 0.2]
+//! inner_angle = 15.0
+//! outer_angle = 25.0
+//! shadows = false
+//!
+//! [[cameras]]
+//! eye = [1.0, 1.0, 3.0]
+//! output = "a.png"
+//!
+//! [[objects]]
+//! model = "obj/diablo3/diablo3_pose.obj"
+//! shader = "phong"
+//! diffuse = "obj/african_head/african_head_diffuse.tga"
+//! normal = "obj/african_head/african_head_nm.tga"
+//! normal_space = "object"
+//! specular = "obj/african_head/african_head_spec.tga"
+//! ao_map = "diablo3_ao.png"
+//! translation = [0.5, 0.0, 0.0]
+//! rotation = [0.0, 30.0, 0.0]
+//! scale = [0.5, 0.5, 0.5]
+//! ```
+//!
+//! 文件中的相对路径相对于场景文件所在目录，相机的输出文件相对于 output_dir
+//!
+//! 只有一个平行光时也可以写成 `[light]`，没有光源时使用默认的平行光
+//!
+//! 模型的 MTL 材质里有贴图时优先使用材质的贴图，场景里指定的贴图作为后备
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use glm::Mat4;
+use num::One;
+use serde::Deserialize;
+
+use crate::{
+    bounding_sphere,
+    draw::{
+        light::{Attenuation, Light},
+        lookat,
+        msaa::SampleCount,
+        shadow::ShadowMap,
+        shadow_filter::{PcfKernel, ShadowFilter},
+        ssaa::{self, DownsampleFilter},
+        ssao::Ssao,
+        texture::{FilterMode, MipmapMode, Sampler, WrapMode},
+        CullMode, FrontFace, RasterizerState,
+    },
+    error::{RenderError, Result},
+    model::{load_texture, Model, Texture},
+    vec4_to_3, GouraudShader, PbrMaterial, PbrShader, PhongShader, Renderer, ShadowShader,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    #[serde(default)]
+    pub settings: RenderSettings,
+    /// 单个光源的简写，和lights合并
+    pub light: Option<LightDesc>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    pub cameras: Vec<CameraDesc>,
+    pub objects: Vec<ObjectDesc>,
+    /// 相机输出文件所在的目录，相对于场景文件，不存在时自动创建，默认是场景文件所在目录
+    #[serde(default)]
+    pub output_dir: PathBuf,
+    /// 场景文件所在目录，用来解析相对路径
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    /// 是否渲染阴影贴图
+    pub shadows: bool,
+    pub shadow_bias: f32,
+    pub shadow_filter: ShadowFilterDesc,
+    /// pcss时光源的大小(阴影贴图像素)
+    pub light_size: f32,
+    /// 用屏幕空间环境光遮蔽调暗phong物体的环境光
+    pub ssao: bool,
+    pub cull: CullMode,
+    /// 正面三角形的环绕方向
+    pub front_face: FrontFace,
+    /// 每个像素的采样点数 1/2/4/8
+    pub msaa: u32,
+    /// 超采样倍数，1表示关闭
+    pub ssaa: u32,
+    pub ssaa_filter: DownsampleFilter,
+    pub texture_filter: FilterMode,
+    pub texture_wrap: WrapMode,
+    pub mipmap: MipmapMode,
+    /// 各向异性过滤的最大采样次数，1表示关闭
+    pub anisotropy: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 800,
+            shadows: true,
+            shadow_bias: ShadowMap::DEFAULT_BIAS,
+            shadow_filter: ShadowFilterDesc::Hard,
+            light_size: 8.,
+            ssao: false,
+            cull: CullMode::Back,
+            front_face: FrontFace::Ccw,
+            msaa: 1,
+            ssaa: 1,
+            ssaa_filter: DownsampleFilter::Box,
+            texture_filter: FilterMode::Bilinear,
+            texture_wrap: WrapMode::Repeat,
+            mipmap: MipmapMode::Linear,
+            anisotropy: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShadowFilterDesc {
+    Hard,
+    Pcf3x3,
+    Pcf5x5,
+    Poisson,
+    Pcss,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightKindDesc {
+    #[default]
+    Directional,
+    Point,
+    Spot,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LightDesc {
+    #[serde(rename = "type")]
+    pub kind: LightKindDesc,
+    /// 平行光指向光源的方向，聚光灯照射的方向
+    pub direction: [f32; 3],
+    /// 点光源和聚光灯的位置
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// 距离衰减的常数项、一次项和二次项
+    pub attenuation: [f32; 3],
+    /// 聚光灯的内外锥角(角度)，和方向的夹角
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    /// 是否为这个光源渲染阴影贴图，还需要settings.shadows开启
+    pub shadows: bool,
+}
+
+impl Default for LightDesc {
+    fn default() -> Self {
+        let attenuation = Attenuation::default();
+        Self {
+            kind: LightKindDesc::Directional,
+            direction: [1., 1., 0.],
+            position: [0., 0., 0.],
+            color: [1., 1., 1.],
+            intensity: 1.,
+            attenuation: [
+                attenuation.constant,
+                attenuation.linear,
+                attenuation.quadratic,
+            ],
+            inner_angle: 20.,
+            outer_angle: 30.,
+            shadows: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    #[serde(default = "default_eye")]
+    pub eye: [f32; 3],
+    #[serde(default)]
+    pub center: [f32; 3],
+    #[serde(default = "default_up")]
+    pub up: [f32; 3],
+    /// 投影时的缩放
+    #[serde(default = "default_zoom")]
+    pub zoom: f32,
+    pub output: PathBuf,
+    pub depth_output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShaderDesc {
+    Gouraud,
+    Phong,
+    Pbr,
+    #[serde(alias = "depth")]
+    Shadow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalSpaceDesc {
+    #[default]
+    Object,
+    Tangent,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectDesc {
+    pub model: PathBuf,
+    pub shader: ShaderDesc,
+    pub diffuse: Option<PathBuf>,
+    pub normal: Option<PathBuf>,
+    /// 法线贴图所在的空间，对phong和pbr有效
+    #[serde(default)]
+    pub normal_space: NormalSpaceDesc,
+    pub specular: Option<PathBuf>,
+    /// 烘焙的环境光遮蔽贴图，对phong和pbr有效，见 ao_bake::AoBake
+    pub ao_map: Option<PathBuf>,
+    /// glTF的金属度-粗糙度贴图，只对pbr有效，和下面的系数相乘
+    pub metallic_roughness: Option<PathBuf>,
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+    /// 自发光贴图，只对pbr有效
+    pub emissive: Option<PathBuf>,
+    #[serde(default)]
+    pub translation: [f32; 3],
+    /// 欧拉角(角度)，按x,y,z的顺序旋转
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default = "default_scale")]
+    pub scale: [f32; 3],
+}
+
+fn default_eye() -> [f32; 3] {
+    [1., 1., 3.]
+}
+
+fn default_up() -> [f32; 3] {
+    [0., 1., 0.]
+}
+
+fn default_zoom() -> f32 {
+    0.75
+}
+
+fn default_roughness() -> f32 {
+    0.5
+}
+
+fn default_scale() -> [f32; 3] {
+    [1., 1., 1.]
+}
+
+fn vec3(v: [f32; 3]) -> glm::Vec3 {
+    glm::vec3(v[0], v[1], v[2])
+}
+
+impl ShadowFilterDesc {
+    fn filter(self, light_size: f32) -> ShadowFilter {
+        match self {
+            ShadowFilterDesc::Hard => ShadowFilter::Hard,
+            ShadowFilterDesc::Pcf3x3 => ShadowFilter::Pcf(PcfKernel::K3x3),
+            ShadowFilterDesc::Pcf5x5 => ShadowFilter::Pcf(PcfKernel::K5x5),
+            ShadowFilterDesc::Poisson => ShadowFilter::Pcf(PcfKernel::Poisson),
+            ShadowFilterDesc::Pcss => ShadowFilter::Pcss {
+                kernel: PcfKernel::Poisson,
+                light_size,
+            },
+        }
+    }
+}
+
+impl RenderSettings {
+    fn sampler(&self) -> Sampler {
+        Sampler {
+            mipmap: self.mipmap,
+            max_anisotropy: self.anisotropy,
+            ..Sampler::new(self.texture_filter, self.texture_wrap)
+        }
+    }
+
+    fn rasterizer_state(&self) -> RasterizerState {
+        RasterizerState {
+            cull_mode: self.cull,
+            front_face: self.front_face,
+            ..Default::default()
+        }
+    }
+}
+
+impl LightDesc {
+    fn validate(&self) -> std::result::Result<(), &'static str> {
+        let needs_direction = self.kind != LightKindDesc::Point;
+        if needs_direction && glm::length(vec3(self.direction)) <= f32::EPSILON {
+            return Err("light direction must not be a zero vector");
+        }
+        if self.intensity < 0. || self.attenuation.iter().any(|&a| a < 0.) {
+            return Err("light intensity and attenuation must not be negative");
+        }
+        if self.kind == LightKindDesc::Spot
+            && !(0. <= self.inner_angle
+                && self.inner_angle <= self.outer_angle
+                && self.outer_angle < 90.)
+        {
+            return Err("spot light angles must satisfy 0 <= inner_angle <= outer_angle < 90");
+        }
+        Ok(())
+    }
+
+    fn light(&self) -> Light {
+        let color = vec3(self.color);
+        let [constant, linear, quadratic] = self.attenuation;
+        let attenuation = Attenuation {
+            constant,
+            linear,
+            quadratic,
+        };
+        match self.kind {
+            LightKindDesc::Directional => Light::Directional {
+                direction: glm::normalize(vec3(self.direction)),
+                color,
+                intensity: self.intensity,
+            },
+            LightKindDesc::Point => Light::Point {
+                position: vec3(self.position),
+                color,
+                intensity: self.intensity,
+                attenuation,
+            },
+            LightKindDesc::Spot => Light::Spot {
+                position: vec3(self.position),
+                direction: glm::normalize(vec3(self.direction)),
+                color,
+                intensity: self.intensity,
+                attenuation,
+                inner_angle: self.inner_angle.to_radians(),
+                outer_angle: self.outer_angle.to_radians(),
+            },
+        }
+    }
+}
+
+impl ObjectDesc {
+    /// 模型矩阵 平移*旋转*缩放
+    pub fn model_matrix(&self) -> Mat4 {
+        let r = self.rotation;
+        let m = glm::ext::translate(&Mat4::one(), vec3(self.translation));
+        let m = glm::ext::rotate(&m, r[0].to_radians(), glm::vec3(1., 0., 0.));
+        let m = glm::ext::rotate(&m, r[1].to_radians(), glm::vec3(0., 1., 0.));
+        let m = glm::ext::rotate(&m, r[2].to_radians(), glm::vec3(0., 0., 1.));
+        glm::ext::scale(&m, vec3(self.scale))
+    }
+}
+
+impl Scene {
+    /// 按扩展名解析场景文件，.json 为JSON，其余按TOML解析
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| RenderError::io(path, e))?;
+        let error = |message: String| RenderError::Scene {
+            path: path.to_path_buf(),
+            message,
+        };
+        let mut scene: Scene = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text).map_err(|e| error(e.to_string()))?,
+            _ => toml::from_str(&text).map_err(|e| error(e.to_string()))?,
+        };
+        scene.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        scene.validate().map_err(|e| error(e.into()))?;
+        Ok(scene)
+    }
+
+    fn validate(&self) -> std::result::Result<(), &'static str> {
+        if self.settings.width == 0 || self.settings.height == 0 {
+            return Err("render size must be positive");
+        }
+        if SampleCount::from_count(self.settings.msaa).is_none() {
+            return Err("msaa must be one of 1, 2, 4, 8");
+        }
+        if !(1..=ssaa::MAX_FACTOR).contains(&self.settings.ssaa) {
+            return Err("ssaa must be between 1 and 16");
+        }
+        if self.settings.anisotropy == 0 {
+            return Err("anisotropy must be positive");
+        }
+        if self.cameras.is_empty() {
+            return Err("scene has no cameras");
+        }
+        for light in self.lights() {
+            light.validate()?;
+        }
+        let unit = 0. ..=1.;
+        if self
+            .objects
+            .iter()
+            .any(|o| !unit.contains(&o.metallic) || !unit.contains(&o.roughness))
+        {
+            return Err("metallic and roughness must be in [0,1]");
+        }
+        Ok(())
+    }
+
+    /// light和lights合并后的所有光源，都没有写时是默认的平行光
+    pub fn lights(&self) -> Vec<LightDesc> {
+        let lights: Vec<_> = self.light.iter().chain(&self.lights).cloned().collect();
+        if lights.is_empty() {
+            vec![LightDesc::default()]
+        } else {
+            lights
+        }
+    }
+
+    // 所有物体变换到世界坐标后的包围球
+    fn bounds(&self, assets: &Assets) -> (glm::Vec3, f32) {
+        let positions = self.objects.iter().flat_map(|obj| {
+            let model_matrix = obj.model_matrix();
+            assets.models[&obj.model]
+                .batches
+                .iter()
+                .flat_map(|batch| batch.mesh.vertices.iter())
+                .map(move |v| {
+                    vec4_to_3(model_matrix * glm::Vec3::from_array(&v.position).extend(1.))
+                })
+        });
+        bounding_sphere(positions).unwrap_or((glm::vec3(0., 0., 0.), 1.))
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.base_dir.join(path)
+    }
+
+    /// 渲染场景中的每个相机，并保存到各自的输出文件
+    pub fn render(&self) -> Result<()> {
+        let assets = Assets::load(self)?;
+        let settings = &self.settings;
+        let (width, height) = (settings.width, settings.height);
+        let descs = self.lights();
+        let lights: Vec<Light> = descs.iter().map(LightDesc::light).collect();
+        let output_dir = self.resolve(&self.output_dir);
+        fs::create_dir_all(&output_dir).map_err(|e| RenderError::io(&output_dir, e))?;
+
+        // 阴影贴图包含所有物体，和相机无关，每个投射阴影的光源一张
+        let shadow_maps = if settings.shadows {
+            let (center, radius) = self.bounds(&assets);
+            let mut shadow_maps = Vec::with_capacity(lights.len());
+            for (desc, light) in descs.iter().zip(&lights) {
+                if !desc.shadows {
+                    shadow_maps.push(None);
+                    continue;
+                }
+                let mut shadow_map = ShadowMap::for_light(
+                    light,
+                    center,
+                    radius,
+                    width,
+                    height,
+                    settings.shadow_bias,
+                );
+                shadow_map.set_filter(settings.shadow_filter.filter(settings.light_size));
+                for obj in &self.objects {
+                    for batch in &assets.models[&obj.model].batches {
+                        shadow_map.render_transformed(&batch.mesh, obj.model_matrix())?;
+                    }
+                }
+                shadow_maps.push(Some(shadow_map));
+            }
+            shadow_maps
+        } else {
+            Vec::new()
+        };
+
+        for camera in &self.cameras {
+            let mut renderer = Renderer::new(width, height);
+            renderer.set_rasterizer_state(settings.rasterizer_state());
+            renderer.set_samples(SampleCount::from_count(settings.msaa).unwrap_or_default());
+            renderer.set_supersampling(settings.ssaa, settings.ssaa_filter)?;
+            let z = camera.zoom;
+            #[rustfmt::skip]
+            renderer.set_projection(glm::mat4(
+                z, 0., 0., 0.,
+                0., z, 0., 0.,
+                0., 0., z, 0.,
+                0., 0., 0., 1.));
+            let projection = renderer.projection();
+            let center = vec3(camera.center);
+            let view = lookat(vec3(camera.eye), center, vec3(camera.up));
+
+            // 环境光遮蔽需要所有物体的深度，渲染完再清空
+            let ambient_occlusion = if settings.ssao {
+                for obj in &self.objects {
+                    for batch in &assets.models[&obj.model].batches {
+                        renderer.draw_depth(&batch.mesh, view * obj.model_matrix())?;
+                    }
+                }
+                let world_to_screen = renderer.render_view_port() * projection * view;
+                let ao = Ssao::default().compute(renderer.depth_buffer(), None, world_to_screen)?;
+                renderer.clear();
+                Some(ao)
+            } else {
+                None
+            };
+
+            for obj in &self.objects {
+                let model_matrix = obj.model_matrix();
+                let model_view = view * model_matrix;
+                // 材质里的贴图优先，场景里指定的贴图作为后备
+                let texture = |path: &Option<PathBuf>| path.as_ref().map(|p| &assets.textures[p]);
+                for batch in &assets.models[&obj.model].batches {
+                    let mesh = &batch.mesh;
+                    match obj.shader {
+                        ShaderDesc::Gouraud => {
+                            let mut shader = GouraudShader::new(
+                                mesh,
+                                batch.diffuse(texture(&obj.diffuse)),
+                                model_view,
+                                projection,
+                                &lights,
+                            )?
+                            .with_sampler(settings.sampler())
+                            .with_model_matrix(model_matrix);
+                            for (i, shadow_map) in shadow_maps.iter().enumerate() {
+                                if let Some(shadow_map) = shadow_map {
+                                    shader = shader.with_light_shadow(i, shadow_map);
+                                }
+                            }
+                            renderer.draw_mesh_parallel(mesh, &shader)?;
+                        }
+                        ShaderDesc::Phong => {
+                            let normal = batch.normal(texture(&obj.normal)).ok_or_else(|| {
+                                RenderError::MissingTexture {
+                                    model: obj.model.clone(),
+                                    material: batch.material.name.clone(),
+                                    kind: "normal",
+                                }
+                            })?;
+                            let mut shader = PhongShader::new(
+                                mesh,
+                                batch.diffuse(texture(&obj.diffuse)),
+                                normal,
+                                batch.specular(texture(&obj.specular)),
+                                model_matrix,
+                                projection * view,
+                                vec3(camera.eye),
+                                &lights,
+                            )?
+                            .with_sampler(settings.sampler());
+                            if obj.normal_space == NormalSpaceDesc::Tangent {
+                                shader = shader.with_tangents(&batch.tangents);
+                            }
+                            for (i, shadow_map) in shadow_maps.iter().enumerate() {
+                                if let Some(shadow_map) = shadow_map {
+                                    shader = shader.with_light_shadow(i, shadow_map);
+                                }
+                            }
+                            if let Some(ao) = &ambient_occlusion {
+                                shader = shader.with_ambient_occlusion(ao);
+                            }
+                            if let Some(occlusion_map) = texture(&obj.ao_map) {
+                                shader = shader.with_occlusion_map(occlusion_map);
+                            }
+                            renderer.draw_mesh_parallel(mesh, &shader)?;
+                        }
+                        ShaderDesc::Pbr => {
+                            let fallback = PbrMaterial {
+                                base_color: texture(&obj.diffuse),
+                                metallic_roughness: texture(&obj.metallic_roughness),
+                                metallic_factor: obj.metallic,
+                                roughness_factor: obj.roughness,
+                                normal: texture(&obj.normal),
+                                occlusion: texture(&obj.ao_map),
+                                emissive: texture(&obj.emissive),
+                                ..Default::default()
+                            };
+                            let mut shader = PbrShader::new(
+                                mesh,
+                                PbrMaterial::for_batch(batch, fallback),
+                                model_matrix,
+                                projection * view,
+                                vec3(camera.eye),
+                                &lights,
+                            )?
+                            .with_sampler(settings.sampler());
+                            if obj.normal_space == NormalSpaceDesc::Tangent {
+                                shader = shader.with_tangents(&batch.tangents);
+                            }
+                            for (i, shadow_map) in shadow_maps.iter().enumerate() {
+                                if let Some(shadow_map) = shadow_map {
+                                    shader = shader.with_light_shadow(i, shadow_map);
+                                }
+                            }
+                            renderer.draw_mesh_parallel(mesh, &shader)?;
+                        }
+                        ShaderDesc::Shadow => {
+                            let shader = ShadowShader::new(
+                                mesh,
+                                model_view,
+                                projection,
+                                renderer.view_port(),
+                            );
+                            renderer.draw_mesh_parallel(mesh, &shader)?;
+                        }
+                    }
+                }
+            }
+
+            renderer.save_image(output_dir.join(&camera.output))?;
+            if let Some(depth_output) = &camera.depth_output {
+                renderer.save_depth(output_dir.join(depth_output))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 场景用到的模型和贴图，同一个文件只加载一次
+struct Assets {
+    models: HashMap<PathBuf, Model>,
+    textures: HashMap<PathBuf, Texture>,
+}
+
+impl Assets {
+    fn load(scene: &Scene) -> Result<Assets> {
+        let mut assets = Assets {
+            models: HashMap::new(),
+            textures: HashMap::new(),
+        };
+        for obj in &scene.objects {
+            if !assets.models.contains_key(&obj.model) {
+                let model = Model::load(scene.resolve(&obj.model))?;
+                assets.models.insert(obj.model.clone(), model);
+            }
+            for texture in [
+                &obj.diffuse,
+                &obj.normal,
+                &obj.specular,
+                &obj.ao_map,
+                &obj.metallic_roughness,
+                &obj.emissive,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if !assets.textures.contains_key(texture) {
+                    let image = load_texture(scene.resolve(texture))?;
+                    assets.textures.insert(texture.clone(), image);
+                }
+            }
+        }
+        Ok(assets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 把TOML场景原样转换成JSON，两种格式解析的结果应该相同
+    #[test]
+    fn scene_files_load_as_toml_and_json() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let tmp = std::env::temp_dir().join(format!("tinyrenderer-scene-{}", std::process::id()));
+        fs::create_dir_all(&tmp).unwrap();
+        let mut loaded = 0;
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            let toml_scene = Scene::load(&path).unwrap();
+            assert_eq!(toml_scene.base_dir, dir);
+
+            let value: toml::Value = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            let json = tmp.join(path.with_extension("json").file_name().unwrap());
+            fs::write(&json, serde_json::to_string(&value).unwrap()).unwrap();
+            let mut json_scene = Scene::load(&json).unwrap();
+            assert_eq!(json_scene.base_dir, tmp);
+
+            json_scene.base_dir = toml_scene.base_dir.clone();
+            assert_eq!(format!("{:?}", json_scene), format!("{:?}", toml_scene));
+            loaded += 1;
+        }
+        assert!(loaded > 0);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let tmp =
+            std::env::temp_dir().join(format!("tinyrenderer-bad-{}.toml", std::process::id()));
+        fs::write(
+            &tmp,
+            "[settings]\nmsaa = 3\n[[cameras]]\noutput = \"a.png\"\n[[objects]]\nmodel = \"m.obj\"\nshader = \"phong\"\n",
+        )
+        .unwrap();
+        let e = Scene::load(&tmp).unwrap_err();
+        assert!(matches!(e, RenderError::Scene { .. }), "{}", e);
+    }
+}